@@ -1,3 +1,5 @@
+pub mod wasm;
+
 use std::{
     path::PathBuf,
     sync::atomic::{AtomicUsize, Ordering},
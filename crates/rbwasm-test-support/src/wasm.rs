@@ -0,0 +1,85 @@
+//! Tiny hand-assembled modules for unit tests
+
+/// Header and a `() -> ()` type
+fn module_with_one_type() -> Vec<u8> {
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    // type section: () -> ()
+    module.extend([0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+    module
+}
+
+/// Header, a `() -> ()` type and one function of that type
+fn module_with_one_function() -> Vec<u8> {
+    let mut module = module_with_one_type();
+    // function section
+    module.extend([0x03, 0x02, 0x01, 0x00]);
+    module
+}
+
+/// Custom section with the given name and contents, both shorter than 128
+/// bytes
+pub fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut section = vec![0x00, (1 + name.len() + data.len()) as u8, name.len() as u8];
+    section.extend(name.as_bytes());
+    section.extend(data);
+    section
+}
+
+/// Module with an empty function exported as `name`. The code section comes
+/// last, with a body of 2 bytes.
+pub fn module_exporting(name: &str) -> Vec<u8> {
+    let mut module = module_with_one_function();
+    // export section
+    let mut export = vec![0x01, name.len() as u8];
+    export.extend(name.as_bytes());
+    export.extend([0x00, 0x00]);
+    module.push(0x07);
+    module.push(export.len() as u8);
+    module.extend(export);
+    // code section
+    module.extend([0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b]);
+    module
+}
+
+/// Module with a single function named `main` whose body starts at the
+/// returned offset
+pub fn module_with_function() -> (Vec<u8>, u64) {
+    let mut module = module_with_one_function();
+    // code section: one body with a nop
+    module.extend([0x0a, 0x05, 0x01, 0x03, 0x00, 0x01, 0x0b]);
+    let body_offset = module.len() as u64 - 3;
+    // name section: function 0 named "main"
+    module.extend(custom_section(
+        "name",
+        &[0x01, 0x07, 0x01, 0x00, 0x04, b'm', b'a', b'i', b'n'],
+    ));
+    (module, body_offset)
+}
+
+/// Module importing `() -> ()` functions and optionally a memory of one page,
+/// each as `(module, name)`, with sections shorter than 128 bytes. Without
+/// code it also serves as a relocatable object with undefined functions.
+pub fn module_importing(functions: &[(&str, &str)], memory: Option<(&str, &str)>) -> Vec<u8> {
+    let mut module = module_with_one_type();
+    let entries = functions
+        .iter()
+        .map(|import| (import, &[0x00, 0x00][..]))
+        .chain(
+            memory
+                .iter()
+                .map(|import| (import, &[0x02, 0x00, 0x01][..])),
+        );
+    let mut imports = vec![functions.len() as u8 + memory.is_some() as u8];
+    for ((import_module, name), desc) in entries {
+        imports.push(import_module.len() as u8);
+        imports.extend(import_module.as_bytes());
+        imports.push(name.len() as u8);
+        imports.extend(name.as_bytes());
+        imports.extend(desc);
+    }
+    // import section
+    module.push(0x02);
+    module.push(imports.len() as u8);
+    module.extend(imports);
+    module
+}
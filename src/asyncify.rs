@@ -0,0 +1,189 @@
+//! Arguments of the Asyncify pass of wasm-opt
+//!
+//! Function lists are passed to wasm-opt through response files (`@@path`)
+//! since they can get longer than a command line allows. Names may contain
+//! `*` wildcards as wasm-opt accepts.
+
+use std::{io::Write, path::Path};
+
+use anyhow::{bail, Context};
+
+use crate::{wasm, Workspace};
+
+#[derive(Debug, Clone, Default)]
+pub struct AsyncifyConfig {
+    /// Imports which may unwind the stack, as `module.name`. If empty, no
+    /// import is assumed to unwind.
+    pub imports: Vec<String>,
+    /// Only these functions are instrumented
+    pub onlylist: Vec<String>,
+    /// Functions never instrumented
+    pub removelist: Vec<String>,
+    /// Functions instrumented in addition to the ones found by analysis
+    pub addlist: Vec<String>,
+    /// Assume calls through the table may unwind, for modules linked later
+    pub relocatable: bool,
+}
+
+impl AsyncifyConfig {
+    /// Check the lists against each other before building anything
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // onlylist decides instrumented functions by itself
+        if !self.onlylist.is_empty() {
+            if !self.removelist.is_empty() {
+                bail!("asyncify onlylist can't be combined with removelist");
+            }
+            if !self.addlist.is_empty() {
+                bail!("asyncify onlylist can't be combined with addlist");
+            }
+        }
+        for name in &self.imports {
+            match name.split_once('.') {
+                Some((module, field)) if !module.is_empty() && !field.is_empty() => {}
+                _ => bail!("asyncify import must be in MODULE.NAME form: {}", name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if any listed function or import doesn't match the module at
+    /// `path`, which is the linked module before asyncify
+    pub fn verify(&self, path: &Path) -> anyhow::Result<()> {
+        let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        self.verify_names(&module)
+            .with_context(|| format!("asyncify lists don't match {:?}", path))
+    }
+
+    fn verify_names(&self, module: &[u8]) -> anyhow::Result<()> {
+        let imports = wasm::function_imports(module)?;
+        check_list("imports", &self.imports, &imports)?;
+        let lists = [
+            ("onlylist", &self.onlylist),
+            ("removelist", &self.removelist),
+            ("addlist", &self.addlist),
+        ];
+        if lists.iter().all(|(_, list)| list.is_empty()) {
+            return Ok(());
+        }
+        let functions = match wasm::function_names(module)? {
+            Some(functions) => functions,
+            None => {
+                log::warn!("module has no function names; asyncify lists are not verified");
+                return Ok(());
+            }
+        };
+        for (kind, list) in lists {
+            check_list(kind, list, &functions)?;
+        }
+        Ok(())
+    }
+
+    /// `--pass-arg` options for wasm-opt, with lists written to response
+    /// files in the workspace
+    pub fn pass_args(&self, workspace: &Workspace) -> anyhow::Result<Vec<String>> {
+        let mut args = vec![];
+        if self.imports.is_empty() {
+            args.push(String::from("--pass-arg=asyncify-ignore-imports"));
+        }
+        let lists = [
+            ("imports", &self.imports),
+            ("onlylist", &self.onlylist),
+            ("removelist", &self.removelist),
+            ("addlist", &self.addlist),
+        ];
+        for (kind, list) in lists {
+            if list.is_empty() {
+                continue;
+            }
+            let response_file = workspace.tempfile(&format!("asyncify-{}", kind), |file| {
+                for name in list {
+                    writeln!(file, "{}", name)?;
+                }
+                Ok(())
+            })?;
+            args.push(format!(
+                "--pass-arg=asyncify-{}@@{}",
+                kind,
+                response_file.to_string_lossy()
+            ));
+        }
+        if self.relocatable {
+            args.push(String::from("--pass-arg=asyncify-relocatable"));
+        }
+        Ok(args)
+    }
+}
+
+/// Whether `name` matches `pattern` having `*` wildcards
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no wildcard
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn check_list(kind: &str, list: &[String], names: &[String]) -> anyhow::Result<()> {
+    let unmatched = list
+        .iter()
+        .filter(|pattern| !names.iter().any(|name| matches(pattern, name)))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !unmatched.is_empty() {
+        bail!(
+            "asyncify {} has entries matching nothing in the module: {}",
+            kind,
+            unmatched.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_list, matches, AsyncifyConfig};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("rb_eval_string", "rb_eval_string"));
+        assert!(!matches("rb_eval", "rb_eval_string"));
+        assert!(matches("rb_*", "rb_eval_string"));
+        assert!(matches("*_string", "rb_eval_string"));
+        assert!(matches("rb_*_str*", "rb_eval_string"));
+        assert!(!matches("rb_*_int", "rb_eval_string"));
+        assert!(matches("*", "main"));
+    }
+
+    #[test]
+    fn test_validate_asyncify_config() {
+        let names = vec![String::from("rb_eval_string"), String::from("main")];
+        assert!(check_list("onlylist", &[String::from("rb_*")], &names).is_ok());
+        assert!(check_list("onlylist", &[String::from("ruby_*")], &names).is_err());
+
+        let config = AsyncifyConfig {
+            onlylist: vec![String::from("main")],
+            removelist: vec![String::from("rb_eval_string")],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = AsyncifyConfig {
+            imports: vec![String::from("env")],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Capturing output of build commands into log files
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+use crate::{is_debugging, relpath_for_display, ui};
+
+/// Number of lines shown from a log when a command failed
+const LOG_TAIL_LINES: usize = 30;
+
+/// Run a command with its stdout and stderr written into a log file.
+/// While debugging, the output is also forwarded to the terminal.
+pub(crate) fn run_logged(cmd: &mut Command, log_path: &Path) -> anyhow::Result<ExitStatus> {
+    let log_file =
+        File::create(log_path).with_context(|| format!("failed to create {:?}", log_path))?;
+    if !is_debugging() {
+        cmd.stdout(log_file.try_clone()?).stderr(log_file);
+        return Ok(cmd.status()?);
+    }
+
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let log_file = Arc::new(Mutex::new(log_file));
+    fn tee<R: Read + Send + 'static, W: Write>(
+        src: R,
+        log_file: Arc<Mutex<File>>,
+        mut terminal: W,
+    ) -> impl FnOnce() -> std::io::Result<()> {
+        move || {
+            for line in BufReader::new(src).split(b'\n') {
+                let mut line = line?;
+                line.push(b'\n');
+                log_file.lock().unwrap().write_all(&line)?;
+                terminal.write_all(&line)?;
+            }
+            Ok(())
+        }
+    }
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdout_tee = std::thread::spawn(tee(stdout, log_file.clone(), std::io::stdout()));
+    let stderr_tee = std::thread::spawn(tee(stderr, log_file, std::io::stderr()));
+    let status = child.wait()?;
+    stdout_tee.join().unwrap()?;
+    stderr_tee.join().unwrap()?;
+    Ok(status)
+}
+
+/// Print the last lines of a failed command's log, and an excerpt of
+/// config.log if the command was configure
+pub(crate) fn report_failure(log_path: &Path, config_log: Option<&Path>) {
+    if let Ok(log) = std::fs::read(log_path) {
+        let log = String::from_utf8_lossy(&log);
+        let lines = log.lines().collect::<Vec<_>>();
+        let tail = &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..];
+        ui::log_excerpt(
+            &format!(
+                "last {} lines of {:?}",
+                tail.len(),
+                relpath_for_display(log_path)
+            ),
+            tail,
+        );
+    }
+    if let Some(config_log) = config_log {
+        let log = std::fs::read(config_log).unwrap_or_default();
+        let log = String::from_utf8_lossy(&log);
+        if let Some(excerpt) = config_log_excerpt(&log) {
+            ui::log_excerpt(
+                &format!("failing test in {:?}", relpath_for_display(config_log)),
+                &excerpt,
+            );
+        }
+    }
+}
+
+/// Extract the last test run by configure from config.log, which is the one
+/// that made configure fail
+fn config_log_excerpt(config_log: &str) -> Option<Vec<&str>> {
+    let lines = config_log.lines().collect::<Vec<_>>();
+    // the tests end where autoconf starts dumping cache variables
+    let end = lines
+        .iter()
+        .position(|line| line.starts_with("## Cache variables"))
+        .map(|end| end.saturating_sub(1))
+        .unwrap_or(lines.len());
+    let start = lines[..end]
+        .iter()
+        .rposition(|line| line.starts_with("configure:") && line.contains(": checking "))?;
+    let mut excerpt = lines[start..end].to_vec();
+    while matches!(excerpt.last(), Some(line) if line.trim().is_empty()) {
+        excerpt.pop();
+    }
+    excerpt.truncate(LOG_TAIL_LINES);
+    Some(excerpt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config_log_excerpt;
+
+    #[test]
+    fn test_config_log_excerpt() {
+        let config_log = "\
+configure:2001: checking for gcc
+configure:2010: result: clang
+configure:3001: checking whether the C compiler works
+configure:3010: clang    conftest.c  >&5
+wasm-ld: error: unable to find library -lfoo
+configure:3015: $? = 1
+configure:3020: error: C compiler cannot create executables
+
+## ---------------- ##
+## Cache variables. ##
+## ---------------- ##
+ac_cv_env_CC_set=set
+";
+        let excerpt = config_log_excerpt(config_log).unwrap();
+        assert_eq!(
+            excerpt.first(),
+            Some(&"configure:3001: checking whether the C compiler works")
+        );
+        assert_eq!(
+            excerpt.last(),
+            Some(&"configure:3020: error: C compiler cannot create executables")
+        );
+    }
+}
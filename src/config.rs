@@ -0,0 +1,267 @@
+//! Build configuration file (`rbwasm.toml`)
+//!
+//! ```toml
+//! command-overrides = ["ruby=shadow", "strip=replace:/opt/llvm/bin/llvm-strip"]
+//!
+//! [[variant]]
+//! name = "minimal"
+//! output = "dist/ruby-minimal.wasm"
+//! exts = "minimal"
+//! profile = "size"
+//!
+//! [[variant]]
+//! name = "full-debug"
+//! output = "dist/ruby-full-debug.wasm"
+//! exts = "full"
+//! debuginfo = true
+//! stack-size = 33554432
+//!
+//! [[variant]]
+//! name = "embedded"
+//! output = "dist/ruby-embedded.wasm"
+//! exec-model = "reactor"
+//! import-memory = true
+//! initial-memory = 33554432
+//! max-memory = 67108864
+//! ```
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    ext::ExtSelection, memory::MemoryConfig, overrides::CommandOverride, postlink::PostLinkStep,
+    BuildProfile, ExecModel,
+};
+
+/// Name of the config file picked up from the current directory
+pub const DEFAULT_CONFIG_FILE: &str = "rbwasm.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Overrides of commands in PATH during `make install`, applied before
+    /// ones given by `--override-command`
+    #[serde(default)]
+    pub command_overrides: Vec<CommandOverride>,
+    /// Binaryen passes run on every linked module in order; see
+    /// [`crate::postlink`]
+    #[serde(default)]
+    pub post_link: Vec<PostLinkStep>,
+    /// Outputs built in one invocation instead of the single `-o`
+    #[serde(default, rename = "variant")]
+    pub variants: Vec<VariantConfig>,
+}
+
+/// Settings of a single output, which may vary between variants
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub output: PathBuf,
+    pub exts: ExtSelection,
+    pub profile: BuildProfile,
+    pub with_debuginfo: bool,
+    /// Move DWARF into a debug file next to the output
+    pub split_debuginfo: bool,
+    pub stack_size: usize,
+    pub asyncify_stack_size: usize,
+    pub memory: MemoryConfig,
+    pub exec_model: ExecModel,
+    pub post_link: Vec<PostLinkStep>,
+}
+
+/// A `[[variant]]` table. Omitted settings are taken from the command line.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VariantConfig {
+    pub name: String,
+    pub output: PathBuf,
+    #[serde(default, deserialize_with = "parse_optional")]
+    pub exts: Option<ExtSelection>,
+    #[serde(default, deserialize_with = "parse_optional")]
+    pub profile: Option<BuildProfile>,
+    pub debuginfo: Option<bool>,
+    pub split_debuginfo: Option<bool>,
+    pub stack_size: Option<usize>,
+    pub asyncify_stack_size: Option<usize>,
+    pub initial_memory: Option<u64>,
+    pub max_memory: Option<u64>,
+    pub import_memory: Option<bool>,
+    pub export_memory: Option<bool>,
+    pub global_base: Option<u64>,
+    #[serde(default, deserialize_with = "parse_optional")]
+    pub exec_model: Option<ExecModel>,
+    /// Replaces the top-level post-link pipeline
+    pub post_link: Option<Vec<PostLinkStep>>,
+}
+
+impl VariantConfig {
+    fn apply(&self, base: &Variant) -> Variant {
+        Variant {
+            name: self.name.clone(),
+            output: self.output.clone(),
+            exts: self.exts.clone().unwrap_or_else(|| base.exts.clone()),
+            profile: self.profile.unwrap_or(base.profile),
+            with_debuginfo: self.debuginfo.unwrap_or(base.with_debuginfo),
+            split_debuginfo: self.split_debuginfo.unwrap_or(base.split_debuginfo),
+            stack_size: self.stack_size.unwrap_or(base.stack_size),
+            asyncify_stack_size: self.asyncify_stack_size.unwrap_or(base.asyncify_stack_size),
+            memory: MemoryConfig {
+                initial: self.initial_memory.or(base.memory.initial),
+                maximum: self.max_memory.or(base.memory.maximum),
+                import: self.import_memory.unwrap_or(base.memory.import),
+                export: self.export_memory.unwrap_or(base.memory.export),
+                global_base: self.global_base.or(base.memory.global_base),
+            },
+            exec_model: self.exec_model.unwrap_or(base.exec_model),
+            post_link: self
+                .post_link
+                .clone()
+                .unwrap_or_else(|| base.post_link.clone()),
+        }
+    }
+}
+
+/// Deserialize a value from the same string form as its command line option
+fn parse_optional<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {:?}", path))?;
+        Self::parse(&content).with_context(|| format!("invalid config file {:?}", path))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Config> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Variants to build, with settings not given in the config file taken
+    /// from `base`
+    pub fn resolve_variants(&self, base: &Variant) -> anyhow::Result<Vec<Variant>> {
+        let variants = self
+            .variants
+            .iter()
+            .map(|variant| variant.apply(base))
+            .collect::<Vec<_>>();
+        for (i, variant) in variants.iter().enumerate() {
+            for other in &variants[..i] {
+                if variant.name == other.name {
+                    bail!("duplicate variant name '{}'", variant.name);
+                }
+                if variant.output == other.output {
+                    bail!(
+                        "variants '{}' and '{}' have the same output {:?}",
+                        other.name,
+                        variant.name,
+                        variant.output
+                    );
+                }
+            }
+        }
+        Ok(variants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{Config, Variant};
+    use crate::{memory::MemoryConfig, overrides::CommandOverride, BuildProfile};
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(r#"command-overrides = ["ruby=shadow"]"#).unwrap();
+        assert_eq!(
+            config.command_overrides,
+            vec![CommandOverride::shadow("ruby")]
+        );
+        assert!(Config::parse(r#"command-overrides = ["ruby=hide"]"#).is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("").unwrap().command_overrides.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_variants() {
+        let config = Config::parse(
+            r#"
+[[variant]]
+name = "small"
+output = "small.wasm"
+profile = "size"
+
+[[variant]]
+name = "debug"
+output = "debug.wasm"
+debuginfo = true
+stack-size = 1024
+import-memory = true
+max-memory = 67108864
+"#,
+        )
+        .unwrap();
+        let base = Variant {
+            name: String::from("default"),
+            output: PathBuf::new(),
+            exts: Default::default(),
+            profile: BuildProfile::Release,
+            with_debuginfo: false,
+            split_debuginfo: false,
+            stack_size: 16777216,
+            asyncify_stack_size: 6144,
+            memory: MemoryConfig {
+                initial: Some(33554432),
+                ..Default::default()
+            },
+            exec_model: Default::default(),
+            post_link: vec![],
+        };
+        let variants = config.resolve_variants(&base).unwrap();
+        assert_eq!(variants[0].profile, BuildProfile::Size);
+        assert_eq!(variants[0].stack_size, 16777216);
+        assert_eq!(variants[1].profile, BuildProfile::Release);
+        assert!(variants[1].with_debuginfo);
+        assert_eq!(variants[1].stack_size, 1024);
+        assert_eq!(
+            variants[1].memory,
+            MemoryConfig {
+                initial: Some(33554432),
+                maximum: Some(67108864),
+                import: true,
+                ..Default::default()
+            }
+        );
+
+        let config = Config::parse(
+            r#"
+[[variant]]
+name = "a"
+output = "ruby.wasm"
+
+[[variant]]
+name = "b"
+output = "ruby.wasm"
+"#,
+        )
+        .unwrap();
+        assert!(config.resolve_variants(&base).is_err());
+        assert!(Config::parse(
+            "[[variant]]\nname = \"a\"\noutput = \"a.wasm\"\nprofile = \"fast\""
+        )
+        .is_err());
+    }
+}
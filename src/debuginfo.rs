@@ -0,0 +1,401 @@
+//! DWARF debug info split out of the shipped module
+//!
+//! The stripped module keeps everything but the `.debug_*` custom sections and
+//! gets a `build_id` section and an `external_debug_info` section naming the
+//! debug file. The debug file is the unstripped module with the same
+//! `build_id`. Only debug sections after the code section are removed, so
+//! offsets reported in stack traces of the stripped module are valid in the
+//! debug file as well.
+
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use regex::Regex;
+use siphasher::sip128::{Hasher128, SipHasher13};
+use wasmparser::{Parser, Payload};
+
+use crate::wasm;
+
+const CODE_SECTION_ID: u8 = 10;
+const BUILD_ID_SECTION: &str = "build_id";
+const EXTERNAL_DEBUG_INFO_SECTION: &str = "external_debug_info";
+
+/// Debug file placed next to `output`, e.g. `ruby.debug.wasm` for `ruby.wasm`
+pub fn debug_file_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{}.debug.wasm", stem))
+}
+
+fn is_debug_section(section: &wasm::RawSection) -> bool {
+    section
+        .custom_name
+        .is_some_and(|name| name.starts_with(".debug_"))
+}
+
+/// Move DWARF of the module at `path` into `debug_path` and strip it from
+/// `path`. Returns the build id shared by both files.
+pub fn split_debuginfo(path: &Path, debug_path: &Path) -> anyhow::Result<Vec<u8>> {
+    let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+    let sections =
+        wasm::raw_sections(&module).with_context(|| format!("failed to parse {:?}", path))?;
+    let code_index = sections
+        .iter()
+        .position(|section| section.id == CODE_SECTION_ID)
+        .with_context(|| format!("{:?} has no code section", path))?;
+    if !sections[code_index..].iter().any(is_debug_section) {
+        bail!("{:?} has no DWARF debug info to split", path);
+    }
+
+    let mut hasher = SipHasher13::new();
+    hasher.write(&module);
+    let build_id = hasher.finish128().as_bytes().to_vec();
+    let build_id_section =
+        wasm::encode_custom_section(BUILD_ID_SECTION, &length_prefixed(&build_id));
+
+    let mut debug_module = module.clone();
+    debug_module.extend(&build_id_section);
+
+    let debug_file_name = debug_path
+        .file_name()
+        .with_context(|| format!("invalid debug file path {:?}", debug_path))?
+        .to_string_lossy();
+    let external_debug_info = length_prefixed(debug_file_name.as_bytes());
+    let mut stripped = module[..8].to_vec();
+    for (i, section) in sections.iter().enumerate() {
+        if i > code_index && is_debug_section(section) {
+            continue;
+        }
+        stripped.extend(section.encoded);
+    }
+    stripped.extend(&build_id_section);
+    stripped.extend(wasm::encode_custom_section(
+        EXTERNAL_DEBUG_INFO_SECTION,
+        &external_debug_info,
+    ));
+
+    std::fs::write(debug_path, debug_module)
+        .with_context(|| format!("failed to write {:?}", debug_path))?;
+    std::fs::write(path, stripped).with_context(|| format!("failed to write {:?}", path))?;
+    Ok(build_id)
+}
+
+/// Bytes prefixed with their length as LEB128
+fn length_prefixed(bytes: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    wasm::write_leb_u32(&mut data, bytes.len() as u32);
+    data.extend(bytes);
+    data
+}
+
+/// Contents of a custom section holding a single length-prefixed string
+fn custom_section_bytes<'a>(module: &'a [u8], name: &str) -> anyhow::Result<Option<&'a [u8]>> {
+    for section in wasm::raw_sections(module)? {
+        if section.custom_name == Some(name) {
+            let mut pos = 0;
+            let len = wasm::read_leb_u32(section.payload, &mut pos)
+                .with_context(|| format!("malformed {} section", name))?;
+            let bytes = &section.payload[pos..];
+            if len as usize != bytes.len() {
+                bail!("malformed {} section", name);
+            }
+            return Ok(Some(bytes));
+        }
+    }
+    Ok(None)
+}
+
+pub fn build_id(module: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(custom_section_bytes(module, BUILD_ID_SECTION)?.map(|id| id.to_vec()))
+}
+
+/// Debug file named by the `external_debug_info` section of a stripped module
+pub fn external_debug_info(module: &[u8]) -> anyhow::Result<Option<String>> {
+    custom_section_bytes(module, EXTERNAL_DEBUG_INFO_SECTION)?
+        .map(|name| Ok(String::from_utf8(name.to_vec())?))
+        .transpose()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Location {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("<unknown>"))?;
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " at {}:{}", file, line),
+            (Some(file), None) => write!(f, " at {}", file),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Maps offsets in a module to functions and source lines using the name
+/// section and DWARF of a debug file
+pub struct Symbolizer {
+    /// Offset of the code section contents, which DWARF addresses are
+    /// relative to
+    code_offset: u64,
+    /// Body ranges of defined functions with their names
+    functions: Vec<(u64, u64, Option<String>)>,
+    files: Vec<String>,
+    /// `(address, (file, line))` sorted by address. `None` ends a sequence.
+    rows: Vec<(u64, Option<(usize, u64)>)>,
+    build_id: Option<Vec<u8>>,
+}
+
+impl Symbolizer {
+    pub fn load(debug_module: &[u8]) -> anyhow::Result<Symbolizer> {
+        let mut code_offset = None;
+        let mut imported_functions = 0;
+        let mut bodies = vec![];
+        let mut function_names = HashMap::new();
+        let mut dwarf_sections = HashMap::new();
+        for payload in Parser::new(0).parse_all(debug_module) {
+            match payload? {
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        if let wasmparser::ImportSectionEntryType::Function(_) = import?.ty {
+                            imported_functions += 1;
+                        }
+                    }
+                }
+                Payload::CodeSectionStart { range, .. } => code_offset = Some(range.start as u64),
+                Payload::CodeSectionEntry(body) => {
+                    let range = body.range();
+                    bodies.push((range.start as u64, range.end as u64));
+                }
+                Payload::CustomSection {
+                    name,
+                    data,
+                    data_offset,
+                    ..
+                } => {
+                    if name == "name" {
+                        wasm::collect_function_names(data, data_offset, &mut function_names)?;
+                    } else if name.starts_with(".debug_") {
+                        dwarf_sections.insert(name.to_string(), data);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let functions = bodies
+            .into_iter()
+            .enumerate()
+            .map(|(i, (start, end))| {
+                let index = imported_functions + i as u32;
+                (start, end, function_names.remove(&index))
+            })
+            .collect();
+
+        let dwarf = gimli::Dwarf::load(|id| -> anyhow::Result<_> {
+            let data = dwarf_sections.get(id.name()).copied().unwrap_or(&[]);
+            Ok(gimli::EndianSlice::new(data, gimli::LittleEndian))
+        })?;
+        let mut files = vec![];
+        let mut rows = vec![];
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+            let mut file_indices = HashMap::new();
+            let mut sequence = vec![];
+            let mut program_rows = program.rows();
+            while let Some((header, row)) = program_rows.next_row()? {
+                if row.end_sequence() {
+                    // sequences of functions discarded by the linker start at
+                    // a tombstone address
+                    let discarded =
+                        matches!(sequence.first(), Some((0, _)) | Some((0xffffffff, _)));
+                    if !discarded && !sequence.is_empty() {
+                        rows.append(&mut sequence);
+                        rows.push((row.address(), None));
+                    }
+                    sequence.clear();
+                    continue;
+                }
+                let file_index = match file_indices.get(&row.file_index()) {
+                    Some(index) => *index,
+                    None => {
+                        let name = match row.file(header) {
+                            Some(file) => file_path(&dwarf, &unit, header, file)?,
+                            None => String::from("<unknown>"),
+                        };
+                        files.push(name);
+                        file_indices.insert(row.file_index(), files.len() - 1);
+                        files.len() - 1
+                    }
+                };
+                let line = row.line().map_or(0, |line| line.get());
+                sequence.push((row.address(), Some((file_index, line))));
+            }
+        }
+        // ends of sequences sort before rows starting at the same address
+        rows.sort_by_key(|(address, row)| (*address, row.is_some()));
+
+        Ok(Symbolizer {
+            code_offset: code_offset.context("module has no code section")?,
+            functions,
+            files,
+            rows,
+            build_id: build_id(debug_module)?,
+        })
+    }
+
+    pub fn build_id(&self) -> Option<&[u8]> {
+        self.build_id.as_deref()
+    }
+
+    /// Location of an offset in the module, as reported in stack traces
+    pub fn lookup(&self, offset: u64) -> Option<Location> {
+        let function = self
+            .functions
+            .iter()
+            .find(|(start, end, _)| (*start..*end).contains(&offset))?;
+        let mut location = Location {
+            function: function.2.clone(),
+            file: None,
+            line: None,
+        };
+        let address = offset.checked_sub(self.code_offset)?;
+        let row = self.rows.partition_point(|(row, _)| *row <= address);
+        if let Some((_, Some((file, line)))) = row.checked_sub(1).map(|row| self.rows[row]) {
+            location.file = Some(self.files[file].clone());
+            location.line = Some(line).filter(|line| *line != 0);
+        }
+        Some(location)
+    }
+
+    /// Annotate each line of a stack trace having a hex offset such as
+    /// `wasm-function[123]:0x1a2b` with its location
+    pub fn symbolize_trace(&self, trace: &str) -> String {
+        let offset_pattern = Regex::new(r"0x([0-9a-fA-F]+)").unwrap();
+        let mut output = String::new();
+        for line in trace.lines() {
+            output.push_str(line);
+            let location = offset_pattern
+                .captures(line)
+                .and_then(|captures| u64::from_str_radix(&captures[1], 16).ok())
+                .and_then(|offset| self.lookup(offset));
+            if let Some(location) = location {
+                output.push_str(&format!("\n    at {}", location));
+            }
+            output.push('\n');
+        }
+        output
+    }
+}
+
+fn file_path<R: gimli::Reader>(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    header: &gimli::LineProgramHeader<R>,
+    file: &gimli::FileEntry<R>,
+) -> anyhow::Result<String> {
+    let name = dwarf.attr_string(unit, file.path_name())?;
+    let name = PathBuf::from(name.to_string_lossy()?.as_ref());
+    let dir = match file.directory(header) {
+        Some(dir) => Some(dwarf.attr_string(unit, dir)?),
+        None => unit.comp_dir.clone(),
+    };
+    let path = match dir {
+        Some(dir) if name.is_relative() => {
+            PathBuf::from(dir.to_string_lossy()?.as_ref()).join(name)
+        }
+        _ => name,
+    };
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_id, external_debug_info, split_debuginfo, Location, Symbolizer};
+    use crate::wasm::{encode_custom_section, raw_sections};
+    use rbwasm_test_support::wasm::module_with_function;
+
+    #[test]
+    fn test_split_debuginfo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ruby.wasm");
+        let debug_path = dir.path().join("ruby.debug.wasm");
+        let (mut module, _) = module_with_function();
+        module.extend(encode_custom_section(".debug_info", &[0; 16]));
+        std::fs::write(&path, &module).unwrap();
+
+        let id = split_debuginfo(&path, &debug_path).unwrap();
+        let stripped = std::fs::read(&path).unwrap();
+        let debug = std::fs::read(&debug_path).unwrap();
+        assert_eq!(build_id(&stripped).unwrap(), Some(id.clone()));
+        assert_eq!(build_id(&debug).unwrap(), Some(id));
+        assert_eq!(
+            external_debug_info(&stripped).unwrap().as_deref(),
+            Some("ruby.debug.wasm")
+        );
+        let names = |module| {
+            raw_sections(module)
+                .unwrap()
+                .iter()
+                .filter_map(|section| section.custom_name.map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&stripped),
+            vec!["name", "build_id", "external_debug_info"]
+        );
+        assert_eq!(names(&debug), vec!["name", ".debug_info", "build_id"]);
+        assert!(debug.starts_with(&module));
+
+        // nothing to split any more
+        assert!(split_debuginfo(&path, &debug_path).is_err());
+
+        // names of 128 bytes or more take more than a byte to prefix
+        let long_debug_path = dir.path().join(format!("{}.debug.wasm", "r".repeat(200)));
+        std::fs::write(&path, &module).unwrap();
+        split_debuginfo(&path, &long_debug_path).unwrap();
+        let stripped = std::fs::read(&path).unwrap();
+        assert_eq!(
+            external_debug_info(&stripped).unwrap(),
+            Some(
+                long_debug_path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_symbolize_trace() {
+        let (module, body_offset) = module_with_function();
+        let symbolizer = Symbolizer::load(&module).unwrap();
+        assert_eq!(
+            symbolizer.lookup(body_offset + 1),
+            Some(Location {
+                function: Some("main".to_string()),
+                file: None,
+                line: None,
+            })
+        );
+        assert_eq!(symbolizer.lookup(1), None);
+        let trace = format!(
+            "RuntimeError: unreachable\n    at wasm-function[0]:{:#x}\n",
+            body_offset + 1
+        );
+        assert_eq!(
+            symbolizer.symbolize_trace(&trace),
+            format!("{}    at main\n", trace)
+        );
+    }
+}
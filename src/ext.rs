@@ -0,0 +1,468 @@
+//! Selection of CRuby extensions to be statically linked
+
+use std::{path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::DEFAULT_ENABLED_EXTENSIONS;
+
+/// Extensions split into a parent and sub-extensions that only work together.
+/// Enabling or removing any of them enables or removes the whole group.
+const EXTENSION_GROUPS: [(&str, &[&str]); 2] = [
+    ("json", &["json/generator", "json/parser"]),
+    (
+        "digest",
+        &[
+            "digest/bubblebabble",
+            "digest/md5",
+            "digest/rmd160",
+            "digest/sha1",
+            "digest/sha2",
+        ],
+    ),
+];
+
+/// Extensions found in `ext/` of CRuby releases since 2.7. Names given by the
+/// user are checked against them even when the source tree is not at hand.
+const KNOWN_EXTENSIONS: [&str; 43] = [
+    "bigdecimal",
+    "cgi/escape",
+    "continuation",
+    "coverage",
+    "date",
+    "dbm",
+    "digest",
+    "digest/bubblebabble",
+    "digest/md5",
+    "digest/rmd160",
+    "digest/sha1",
+    "digest/sha2",
+    "erb/escape",
+    "etc",
+    "fcntl",
+    "fiber",
+    "fiddle",
+    "gdbm",
+    "io/console",
+    "io/nonblock",
+    "io/wait",
+    "json",
+    "json/generator",
+    "json/parser",
+    "monitor",
+    "nkf",
+    "objspace",
+    "openssl",
+    "pathname",
+    "psych",
+    "pty",
+    "racc/cparse",
+    "rbconfig/sizeof",
+    "readline",
+    "ripper",
+    "socket",
+    "stringio",
+    "strscan",
+    "syslog",
+    "win32",
+    "win32/resolv",
+    "win32ole",
+    "zlib",
+];
+
+const MINIMAL_EXTENSIONS: [&str; 3] = ["monitor", "rbconfig/sizeof", "stringio"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtPreset {
+    Minimal,
+    Default,
+    /// Every known extension found in the source tree
+    Full,
+}
+
+impl ExtPreset {
+    const NAMES: [&'static str; 3] = ["minimal", "default", "full"];
+
+    fn from_name(name: &str) -> Option<ExtPreset> {
+        match name {
+            "minimal" => Some(ExtPreset::Minimal),
+            "default" => Some(ExtPreset::Default),
+            "full" => Some(ExtPreset::Full),
+            _ => None,
+        }
+    }
+
+    fn extensions(&self, candidates: &[String]) -> Vec<String> {
+        match self {
+            ExtPreset::Minimal => MINIMAL_EXTENSIONS.iter().map(|s| s.to_string()).collect(),
+            ExtPreset::Default => DEFAULT_ENABLED_EXTENSIONS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            ExtPreset::Full => candidates.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ExtDelta {
+    Add(String),
+    Remove(String),
+}
+
+/// Extension selection given by `--exts`, e.g. `+openssl,-ripper` or `minimal,+json`.
+/// Deltas are applied in order to the named preset, or to the defaults if no
+/// preset is named.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtSelection {
+    /// `None` starts from an empty set
+    preset: Option<ExtPreset>,
+    deltas: Vec<ExtDelta>,
+}
+
+impl FromStr for ExtSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut preset = None;
+        let mut deltas = vec![];
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            if let Some(name) = item.strip_prefix('+') {
+                deltas.push(ExtDelta::Add(name.to_string()));
+            } else if let Some(name) = item.strip_prefix('-') {
+                deltas.push(ExtDelta::Remove(name.to_string()));
+            } else if let Some(named) = ExtPreset::from_name(item) {
+                if preset.is_some() {
+                    bail!("only one preset can be given, but found '{}'", item);
+                }
+                preset = Some(named);
+            } else {
+                let mut message = format!(
+                    "unknown preset '{}'; use +{} to add an extension",
+                    item, item
+                );
+                if let Some(suggestion) = did_you_mean(item, ExtPreset::NAMES.iter().copied()) {
+                    message += &format!(" (did you mean '{}'?)", suggestion);
+                }
+                return Err(anyhow!(message));
+            }
+        }
+        Ok(ExtSelection {
+            preset: Some(preset.unwrap_or(ExtPreset::Default)),
+            deltas,
+        })
+    }
+}
+
+impl Default for ExtSelection {
+    fn default() -> Self {
+        ExtSelection {
+            preset: Some(ExtPreset::Default),
+            deltas: vec![],
+        }
+    }
+}
+
+impl ExtSelection {
+    /// Wholesale list of extensions, as given by `--enabled-exts`
+    pub fn exactly(names: &[&str]) -> ExtSelection {
+        ExtSelection {
+            preset: None,
+            deltas: names.iter().map(|n| ExtDelta::Add(n.to_string())).collect(),
+        }
+    }
+
+    /// Resolve the selection into a sorted list of extension names including
+    /// their dependencies. Names given by the user are validated against the
+    /// known extensions, narrowed to the `available` ones in the source tree
+    /// unless it's empty. Preset members out of that list are dropped.
+    pub fn resolve(&self, available: &[String]) -> anyhow::Result<Vec<String>> {
+        let candidates = KNOWN_EXTENSIONS
+            .iter()
+            .map(|name| name.to_string())
+            .filter(|name| available.is_empty() || available.contains(name))
+            .collect::<Vec<_>>();
+        for delta in &self.deltas {
+            let (ExtDelta::Add(name) | ExtDelta::Remove(name)) = delta;
+            validate_name(name, &candidates)?;
+        }
+        let mut enabled = match self.preset {
+            Some(preset) => preset.extensions(&candidates),
+            None => vec![],
+        };
+        // presets may name extensions that the source tree no longer has,
+        // like dbm and gdbm in recent CRuby
+        enabled.retain(|name| candidates.contains(name));
+        let is_candidate = |name: &str| candidates.iter().any(|c| c == name);
+        let mut resolved = vec![];
+        for name in &enabled {
+            enable(&mut resolved, name, &is_candidate);
+        }
+        for delta in &self.deltas {
+            match delta {
+                ExtDelta::Add(name) => enable(&mut resolved, name, &is_candidate),
+                ExtDelta::Remove(name) => {
+                    let group = group_of(name);
+                    resolved
+                        .retain(|enabled| enabled != name && !group.contains(&enabled.as_str()));
+                }
+            }
+        }
+        resolved.sort();
+        Ok(resolved)
+    }
+}
+
+/// Enable an extension with what it requires. A group member requires its
+/// parent, and a parent brings all of its members.
+fn enable(enabled: &mut Vec<String>, name: &str, is_available: &dyn Fn(&str) -> bool) {
+    if enabled.iter().any(|e| e == name) {
+        return;
+    }
+    enabled.push(name.to_string());
+    for dependency in dependencies_of(name) {
+        if is_available(dependency) {
+            enable(enabled, dependency, is_available);
+        }
+    }
+}
+
+/// Returns other extensions enabled along with the given extension
+fn dependencies_of(name: &str) -> Vec<&'static str> {
+    for (parent, children) in EXTENSION_GROUPS {
+        if parent == name {
+            return children.to_vec();
+        }
+        if children.contains(&name) {
+            return vec![parent];
+        }
+    }
+    vec![]
+}
+
+/// Parent and members of the group the given extension belongs to, which
+/// don't work without each other
+fn group_of(name: &str) -> Vec<&'static str> {
+    for (parent, children) in EXTENSION_GROUPS {
+        if parent == name || children.contains(&name) {
+            let mut group = vec![parent];
+            group.extend(children.iter().copied());
+            return group;
+        }
+    }
+    vec![]
+}
+
+fn validate_name(name: &str, candidates: &[String]) -> anyhow::Result<()> {
+    if candidates.iter().any(|c| c == name) {
+        return Ok(());
+    }
+    if KNOWN_EXTENSIONS.contains(&name) {
+        bail!("extension '{}' is not in ext/ of the CRuby source", name);
+    }
+    let mut message = format!("unknown extension '{}'", name);
+    if let Some(suggestion) = did_you_mean(name, candidates.iter().map(String::as_str)) {
+        message += &format!(" (did you mean '{}'?)", suggestion);
+    }
+    Err(anyhow!(message))
+}
+
+/// Collect extension names (e.g. `json`, `digest/md5`) from `ext/` in the CRuby source tree
+pub fn available_extensions(src_dir: &Path) -> anyhow::Result<Vec<String>> {
+    fn visit_dirs(ext_root: &Path, dir: &Path, names: &mut Vec<String>) -> anyhow::Result<()> {
+        for entry in
+            std::fs::read_dir(dir).with_context(|| format!("failed to read dir: {:?}", dir))?
+        {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = path.strip_prefix(ext_root).unwrap().to_string_lossy();
+            // skip test-only extensions like ext/-test-
+            if name.starts_with('-') {
+                continue;
+            }
+            if path.join("extconf.rb").exists() {
+                names.push(name.to_string());
+            }
+            visit_dirs(ext_root, &path, names)?;
+        }
+        Ok(())
+    }
+    let ext_root = src_dir.join("ext");
+    let mut names = vec![];
+    if ext_root.is_dir() {
+        visit_dirs(&ext_root, &ext_root, &mut names)?;
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn did_you_mean<'a, I: Iterator<Item = &'a str>>(name: &str, candidates: I) -> Option<&'a str> {
+    let threshold = std::cmp::max(2, name.len() / 3);
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance, counting adjacent transposition as a single edit
+fn edit_distance(lhs: &str, rhs: &str) -> usize {
+    let lhs = lhs.chars().collect::<Vec<_>>();
+    let rhs = rhs.chars().collect::<Vec<_>>();
+    let mut table = vec![vec![0; rhs.len() + 1]; lhs.len() + 1];
+    for (i, row) in table.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in table[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=lhs.len() {
+        for j in 1..=rhs.len() {
+            let cost = if lhs[i - 1] == rhs[j - 1] { 0 } else { 1 };
+            let mut distance = (table[i - 1][j] + 1)
+                .min(table[i][j - 1] + 1)
+                .min(table[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && lhs[i - 1] == rhs[j - 2] && lhs[i - 2] == rhs[j - 1] {
+                distance = distance.min(table[i - 2][j - 2] + 1);
+            }
+            table[i][j] = distance;
+        }
+    }
+    table[lhs.len()][rhs.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{did_you_mean, ExtSelection};
+
+    fn available() -> Vec<String> {
+        [
+            "json",
+            "json/generator",
+            "json/parser",
+            "monitor",
+            "openssl",
+            "rbconfig/sizeof",
+            "ripper",
+            "stringio",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn test_resolve_deltas() {
+        let selection: ExtSelection = "minimal,+json,-stringio".parse().unwrap();
+        let resolved = selection.resolve(&available()).unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                "json",
+                "json/generator",
+                "json/parser",
+                "monitor",
+                "rbconfig/sizeof"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_full() {
+        let selection: ExtSelection = "full,-ripper".parse().unwrap();
+        let resolved = selection.resolve(&available()).unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                "json",
+                "json/generator",
+                "json/parser",
+                "monitor",
+                "openssl",
+                "rbconfig/sizeof",
+                "stringio"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_default_without_dropped_extensions() {
+        // the default preset still lists dbm and gdbm
+        let resolved = ExtSelection::default().resolve(&available()).unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                "json",
+                "json/generator",
+                "json/parser",
+                "monitor",
+                "rbconfig/sizeof",
+                "ripper",
+                "stringio"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_exactly() {
+        let resolved = ExtSelection::exactly(&["stringio"])
+            .resolve(&available())
+            .unwrap();
+        assert_eq!(resolved, vec!["stringio"]);
+    }
+
+    #[test]
+    fn test_resolve_dependencies() {
+        let resolved = ExtSelection::exactly(&["json/parser"])
+            .resolve(&available())
+            .unwrap();
+        assert_eq!(resolved, vec!["json", "json/generator", "json/parser"]);
+
+        // removing the parent or a member removes the whole group
+        for exts in ["-json", "full,-json", "minimal,+json,-json/parser"] {
+            let selection: ExtSelection = exts.parse().unwrap();
+            let resolved = selection.resolve(&available()).unwrap();
+            assert!(
+                !resolved.iter().any(|name| name.starts_with("json")),
+                "{}: {:?}",
+                exts,
+                resolved
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_extension_suggestion() {
+        let selection: ExtSelection = "minimal,+jsno".parse().unwrap();
+        // checked against the known extensions without the source tree too
+        for available in [available(), vec![]] {
+            let error = selection.resolve(&available).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "unknown extension 'jsno' (did you mean 'json'?)"
+            );
+        }
+        let selection: ExtSelection = "+zlib".parse().unwrap();
+        let error = selection.resolve(&available()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "extension 'zlib' is not in ext/ of the CRuby source"
+        );
+        assert!(selection
+            .resolve(&[])
+            .unwrap()
+            .contains(&"zlib".to_string()));
+    }
+
+    #[test]
+    fn test_unknown_preset() {
+        let error = "minimla".parse::<ExtSelection>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unknown preset 'minimla'; use +minimla to add an extension (did you mean 'minimal'?)"
+        );
+        assert_eq!(did_you_mean("zzz", ["json"].iter().copied()), None);
+    }
+}
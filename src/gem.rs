@@ -0,0 +1,148 @@
+//! Third-party gems with C extensions statically linked into ruby.wasm
+//!
+//! Gem extensions are built as a part of CRuby's `ext/` build, so that their
+//! `extconf.rb` runs against the cross-built Ruby, they are compiled with the
+//! same toolchain, and their init functions are registered in `extinit.c`.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+/// C extension of a gem, located at `<gem>/ext/<name>/extconf.rb`
+#[derive(Debug, Clone, Hash)]
+pub struct GemExt {
+    /// Path of the extension relative to the gem's `ext/`, also used as the
+    /// directory name under CRuby's `ext/`
+    pub name: String,
+    pub ext_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Gem {
+    pub dir: PathBuf,
+    pub exts: Vec<GemExt>,
+}
+
+impl Gem {
+    /// Find C extensions in a gem source directory
+    pub fn discover(gem_dir: &Path) -> anyhow::Result<Gem> {
+        let dir = gem_dir
+            .canonicalize()
+            .with_context(|| format!("gem directory not found: {:?}", gem_dir))?;
+        let ext_root = dir.join("ext");
+        let mut extconfs = vec![];
+        if ext_root.is_dir() {
+            collect_files(&ext_root, &mut extconfs)?;
+        }
+        let mut exts = extconfs
+            .into_iter()
+            .filter(|path| path.file_name() == Some(OsStr::new("extconf.rb")))
+            .map(|extconf| {
+                let ext_dir = extconf.parent().unwrap().to_path_buf();
+                let name = ext_dir
+                    .strip_prefix(&ext_root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                GemExt { name, ext_dir }
+            })
+            .collect::<Vec<_>>();
+        if exts.is_empty() {
+            bail!("no ext/**/extconf.rb found in gem directory {:?}", dir);
+        }
+        exts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Gem { dir, exts })
+    }
+
+    /// Map Ruby files under the gem's `lib/` into `site_ruby`, which is in
+    /// the default load path of the guest Ruby
+    pub fn lib_map_paths(&self) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+        let lib_dir = self.dir.join("lib");
+        let mut files = vec![];
+        if lib_dir.is_dir() {
+            collect_files(&lib_dir, &mut files)?;
+        }
+        files.sort();
+        Ok(files
+            .into_iter()
+            .map(|host| {
+                let guest = Path::new("@ruby_root/lib/ruby/site_ruby")
+                    .join(host.strip_prefix(&lib_dir).unwrap());
+                (guest, host)
+            })
+            .collect())
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("failed to read dir: {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Link gem extensions into `ext/` of a source tree copied for a single
+/// build, so that CRuby builds them along with its own extensions
+pub(crate) fn link_gem_exts(exts: &[GemExt], src_dir: &Path) -> anyhow::Result<()> {
+    for ext in exts {
+        let link = src_dir.join("ext").join(&ext.name);
+        if link.exists() {
+            bail!(
+                "gem extension '{}' conflicts with {:?} in CRuby source",
+                ext.name,
+                link
+            );
+        }
+        if let Some(parent) = link.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::os::unix::fs::symlink(&ext.ext_dir, &link).with_context(|| {
+            format!(
+                "failed to link gem extension {:?} into {:?}",
+                ext.ext_dir, link
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Gem;
+
+    #[test]
+    fn test_discover_gem() {
+        let gem_dir = tempfile::tempdir().unwrap();
+        let root = gem_dir.path();
+        std::fs::create_dir_all(root.join("ext/foo")).unwrap();
+        std::fs::write(root.join("ext/foo/extconf.rb"), "").unwrap();
+        std::fs::write(root.join("ext/foo/foo.c"), "").unwrap();
+        std::fs::create_dir_all(root.join("lib/foo")).unwrap();
+        std::fs::write(root.join("lib/foo/version.rb"), "").unwrap();
+
+        let gem = Gem::discover(root).unwrap();
+        let names = gem
+            .exts
+            .iter()
+            .map(|ext| ext.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["foo"]);
+
+        let map_paths = gem.lib_map_paths().unwrap();
+        assert_eq!(map_paths.len(), 1);
+        assert_eq!(
+            map_paths[0].0,
+            Path::new("@ruby_root/lib/ruby/site_ruby/foo/version.rb")
+        );
+    }
+}
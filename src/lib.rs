@@ -1,6 +1,19 @@
+pub mod asyncify;
+mod buildlog;
+pub mod config;
+pub mod debuginfo;
+pub mod ext;
+pub mod gem;
 mod github;
+pub mod memory;
+pub mod overrides;
+pub mod postlink;
+pub mod reproducible;
+pub mod size_report;
+pub mod summary;
 pub mod toolchain;
 mod ui;
+pub mod wasm;
 use std::{
     fs::File,
     hash::{Hash, Hasher},
@@ -8,19 +21,28 @@ use std::{
     os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context};
 use regex::Regex;
 use siphasher::sip128::SipHasher13;
 
-use crate::toolchain::Toolchain;
+use crate::gem::{link_gem_exts, GemExt};
+use crate::memory::MemoryConfig;
+use crate::overrides::CommandOverride;
+use crate::reproducible::Reproducible;
+use crate::summary::PhaseRecord;
+use crate::toolchain::{BaseRuby, CompilerCache, CompilerCacheStats, Toolchain};
 use crate::ui::trace_command_exec;
 
 pub struct Workspace {
     dir: PathBuf,
     save_temps: bool,
-    tempfile_owner: Vec<tempfile::NamedTempFile>,
+    tempfile_owner: Mutex<Vec<tempfile::NamedTempFile>>,
+    phases: Mutex<Vec<PhaseRecord>>,
 }
 
 impl Workspace {
@@ -28,7 +50,8 @@ impl Workspace {
         let space = Workspace {
             dir,
             save_temps,
-            tempfile_owner: vec![],
+            tempfile_owner: Mutex::new(vec![]),
+            phases: Mutex::new(vec![]),
         };
         std::fs::create_dir_all(space.build_dir())?;
         std::fs::create_dir_all(space.downloads_dir())?;
@@ -54,19 +77,21 @@ impl Workspace {
         self.dir.join("tmp")
     }
 
-    fn with_overriding_command<R, F: FnOnce(PathBuf) -> R>(
+    /// Run `inner` with a directory containing the given command overrides,
+    /// which is expected to be prepended to PATH. Later overrides of the same
+    /// command win.
+    fn with_overriding_commands<R, F: FnOnce(PathBuf) -> R>(
         &self,
-        cmd: &str,
+        overrides: &[CommandOverride],
         inner: F,
     ) -> anyhow::Result<R> {
         let fake_bin_dir = tempfile::tempdir_in(self.temporary_dir())?;
         let fake_bin_dir_path = fake_bin_dir.path().to_path_buf();
-        let fake_bin = fake_bin_dir_path.join(cmd);
-        {
+        for command_override in overrides {
+            let script = command_override.script()?;
+            let fake_bin = fake_bin_dir_path.join(&command_override.name);
             let mut fake_bin = File::create(fake_bin)?;
-            let true_bin =
-                which::which("true").with_context(|| format!("true command not found"))?;
-            fake_bin.write_all(format!("#!{}\n", true_bin.to_string_lossy()).as_bytes())?;
+            fake_bin.write_all(script.as_bytes())?;
             let mut perm = fake_bin.metadata()?.permissions();
             // chmod +x
             perm.set_mode(perm.mode() | 0o111);
@@ -82,7 +107,7 @@ impl Workspace {
     }
 
     pub fn tempfile<F: FnOnce(&mut tempfile::NamedTempFile) -> anyhow::Result<()>>(
-        &mut self,
+        &self,
         prefix: &str,
         inner: F,
     ) -> anyhow::Result<PathBuf> {
@@ -94,18 +119,52 @@ impl Workspace {
         if self.save_temps {
             tmpfile.keep()?;
         } else {
-            self.tempfile_owner.push(tmpfile);
+            self.tempfile_owner.lock().unwrap().push(tmpfile);
         }
 
         Ok(tmpfile_path)
     }
 
-    fn hashed_dirs<T: Hash>(&self, source: T, name: &str) -> (PathBuf, PathBuf) {
+    /// Run a build phase and record how long it took
+    pub fn phase<R, F: FnOnce() -> anyhow::Result<R>>(
+        &self,
+        name: &str,
+        inner: F,
+    ) -> anyhow::Result<R> {
+        let started = Instant::now();
+        let result = inner()?;
+        self.record_phase(name, started.elapsed(), false);
+        Ok(result)
+    }
+
+    pub fn record_phase(&self, name: &str, duration: Duration, cached: bool) {
+        let mut phases = self.phases.lock().unwrap();
+        // a cached result looked up again is not worth another line
+        if cached && phases.iter().any(|phase| phase.name == name) {
+            return;
+        }
+        phases.push(PhaseRecord {
+            name: name.to_string(),
+            duration,
+            cached,
+        });
+    }
+
+    /// Phases recorded so far, in order of their completion
+    pub fn phases(&self) -> Vec<PhaseRecord> {
+        self.phases.lock().unwrap().clone()
+    }
+
+    fn hashed_name<T: Hash>(&self, source: T, name: &str) -> String {
         let mut hasher = SipHasher13::new();
         source.hash(&mut hasher);
         let result = hasher.finish();
         let hex = hex::encode(result.to_le_bytes());
-        let hashed = format!("{}-{}", name, hex);
+        format!("{}-{}", name, hex)
+    }
+
+    fn hashed_dirs<T: Hash>(&self, source: T, name: &str) -> (PathBuf, PathBuf) {
+        let hashed = self.hashed_name(source, name);
         let build_dir = self.build_dir().join(&hashed);
         let install_dir = self.cache_dir().join(&hashed);
         (build_dir, install_dir)
@@ -114,11 +173,26 @@ impl Workspace {
 
 pub struct BuildResult {
     pub install_dir: PathBuf,
+    /// Directory CRuby was built in, which keeps intermediate objects
+    pub build_dir: PathBuf,
     pub cached: bool,
+    /// Prefix of Ruby in the guest filesystem, same as `CRubyBuildInput::prefix`
     pub prefix: PathBuf,
+    /// Compiler cache hits and misses while building CRuby, if a compiler
+    /// cache is used and the build was not cached
+    pub compiler_cache_stats: Option<CompilerCacheStats>,
 }
 
-#[derive(Debug, Hash)]
+impl BuildResult {
+    /// Host directory where the guest prefix is installed
+    pub fn installed_ruby_root(&self) -> PathBuf {
+        // prefix is validated to be absolute before building
+        self.install_dir
+            .join(self.prefix.strip_prefix("/").unwrap_or(&self.prefix))
+    }
+}
+
+#[derive(Debug, Clone, Hash)]
 pub enum BuildSource {
     GitHub {
         owner: String,
@@ -130,24 +204,51 @@ pub enum BuildSource {
     },
 }
 
-/// Retrieve a build source from BuildSource and returns source directory
-fn install_build_src<'a>(source: &'a BuildSource, build_dir: &'a Path) -> anyhow::Result<&'a Path> {
+fn download_dir(workspace: &Workspace, source: &BuildSource) -> PathBuf {
+    workspace
+        .downloads_dir()
+        .join(workspace.hashed_name(source, "ruby-src"))
+}
+
+/// Source directory of a build source if it's already there, without
+/// downloading it
+pub fn existing_build_src(workspace: &Workspace, source: &BuildSource) -> Option<PathBuf> {
+    let src_dir = match source {
+        BuildSource::GitHub { .. } => download_dir(workspace, source),
+        BuildSource::Dir { path } => path.clone(),
+    };
+    // downloads are moved into place only when complete
+    Some(src_dir).filter(|src_dir| src_dir.exists())
+}
+
+/// Retrieve a build source from BuildSource and returns source directory.
+/// Downloaded sources are shared by all builds from the same source, so they
+/// must not be modified; see `copy_build_src`.
+pub fn install_build_src(workspace: &Workspace, source: &BuildSource) -> anyhow::Result<PathBuf> {
     match source {
         BuildSource::GitHub {
             owner,
             repo,
             git_ref,
         } => {
-            if build_dir.exists() {
-                return Ok(build_dir);
+            let src_dir = download_dir(workspace, source);
+            // another rbwasm on the same workspace may be downloading it
+            let lock_path = src_dir.with_extension("lock");
+            let lock = File::create(&lock_path)
+                .with_context(|| format!("failed to create {:?}", lock_path))?;
+            lock.lock()
+                .with_context(|| format!("failed to lock {:?}", lock_path))?;
+            if src_dir.exists() {
+                workspace.record_phase("source download", Duration::ZERO, true);
+                return Ok(src_dir);
             }
+            let started = Instant::now();
             ui_info!(
                 "downloading {}/{} source into {:?}",
                 owner,
                 repo,
-                relpath_for_display(build_dir),
+                relpath_for_display(&src_dir),
             );
-            std::fs::create_dir_all(build_dir)?;
             static APP_USER_AGENT: &str =
                 concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
             let tar_gz = github::repo_archive_download_link(&owner, &repo, &git_ref);
@@ -156,39 +257,38 @@ fn install_build_src<'a>(source: &'a BuildSource, build_dir: &'a Path) -> anyhow
                 .build()?;
             let response = client.get(tar_gz).send()?;
             let mut tar_gz = response.error_for_status()?;
-
-            let mut tar = Command::new("tar")
-                .args(["xz", "--strip-components", "1"])
-                .current_dir(build_dir)
-                .stdin(Stdio::piped())
-                .spawn()?;
-            std::io::copy(&mut tar_gz, &mut tar.stdin.take().unwrap())?;
-            return Ok(build_dir);
+            // extract aside so that an interrupted download is not taken as
+            // a complete source tree
+            let partial_dir = src_dir.with_extension("partial");
+            if partial_dir.exists() {
+                std::fs::remove_dir_all(&partial_dir)
+                    .with_context(|| format!("failed to remove {:?}", partial_dir))?;
+            }
+            extract_tarball(&mut tar_gz, &partial_dir)?;
+            std::fs::rename(&partial_dir, &src_dir)
+                .with_context(|| format!("failed to move source into {:?}", src_dir))?;
+            workspace.record_phase("source download", started.elapsed(), false);
+            Ok(src_dir)
         }
-        BuildSource::Dir { path } => return Ok(path),
+        BuildSource::Dir { path } => Ok(path.clone()),
     }
 }
 
-pub const DEFAULT_ENABLED_EXTENSIONS: [&str; 29] = [
+/// Note that sub-extensions like `json/parser` are enabled through their parent
+/// by `ext::ExtSelection::resolve`
+pub const DEFAULT_ENABLED_EXTENSIONS: [&str; 22] = [
     "bigdecimal",
     "cgi/escape",
     "continuation",
     "coverage",
     "date",
     "dbm",
-    "digest/bubblebabble",
     "digest",
-    "digest/md5",
-    "digest/rmd160",
-    "digest/sha1",
-    "digest/sha2",
     "etc",
     "fcntl",
     "fiber",
     "gdbm",
     "json",
-    "json/generator",
-    "json/parser",
     "nkf",
     "objspace",
     "pathname",
@@ -202,24 +302,25 @@ pub const DEFAULT_ENABLED_EXTENSIONS: [&str; 29] = [
 ];
 
 fn configure_cruby(
+    workspace: &Workspace,
     toolchain: &Toolchain,
     src_dir: &Path,
     build_dir: &Path,
     install_dir: &Path,
-    prefix: &Path,
-    asyncify_stack_size: usize,
-    enabled_extensions: Vec<&str>,
-    extra_cc_args: &[String],
+    input: &CRubyBuildInput,
 ) -> anyhow::Result<()> {
     log::info!("configure cruby");
-    let wasi_sdk = toolchain.wasi_sdk.as_path().to_string_lossy();
 
     std::fs::create_dir_all(build_dir).with_context(|| format!("failed to create build dir"))?;
 
     let configure = src_dir.join("configure").canonicalize()?;
     let ldflags = [
-        format!("--sysroot={}/share/wasi-sysroot", wasi_sdk),
-        format!("-L{}/share/wasi-sysroot/lib/wasm32-wasi", wasi_sdk),
+        toolchain.target_flag().to_string(),
+        toolchain.sysroot_flag(),
+        format!(
+            "-L{}",
+            toolchain.sysroot.join("lib/wasm32-wasi").to_string_lossy()
+        ),
         String::from("-lwasi-emulated-mman"),
         String::from("-lwasi-emulated-signal"),
         String::from("-lwasi-emulated-getpid"),
@@ -228,7 +329,8 @@ fn configure_cruby(
         String::from("--features=mutable-globals"),
     ];
     let mut cflags = vec![
-        format!("--sysroot={}/share/wasi-sysroot", wasi_sdk),
+        toolchain.target_flag().to_string(),
+        toolchain.sysroot_flag(),
         String::from("-D_WASI_EMULATED_SIGNAL"),
         String::from("-D_WASI_EMULATED_MMAN"),
         String::from("-D_WASI_EMULATED_GETPID"),
@@ -236,20 +338,21 @@ fn configure_cruby(
         String::from("-DRB_WASM_SUPPORT_EMULATE_SETJMP"),
         format!(
             "-DRB_WASM_SUPPORT_FRAME_BUFFER_SIZE={}",
-            asyncify_stack_size
+            input.asyncify_stack_size
         ),
     ];
-    cflags.extend(extra_cc_args.to_vec());
-    if let Ok(total_size) = std::env::var("TRANSIENT_HEAP_TOTAL_SIZE") {
+    cflags.extend(input.profile.cflags().iter().map(|flag| flag.to_string()));
+    cflags.extend(input.extra_cc_args.to_vec());
+    if input.reproducible.is_some() {
+        let remaps = cruby_path_remaps(workspace, toolchain, build_dir, install_dir);
+        cflags.extend(reproducible::prefix_map_flags(&remaps));
+    }
+    if let Some(total_size) = input.transient_heap_total_size {
         cflags.push(format!("-DTRANSIENT_HEAP_TOTAL_SIZE={}", total_size));
     }
     let mut configure_cmd = Command::new(configure.as_path());
     configure_cmd.current_dir(&build_dir);
 
-    if !is_debugging() {
-        configure_cmd.stdout(Stdio::null()).stderr(Stdio::null());
-    }
-
     configure_cmd.args([
         "--host=wasm32-unknown-wasi",
         "--disable-install-doc",
@@ -257,33 +360,205 @@ fn configure_cruby(
         "--with-coroutine=asyncify",
         "--with-static-linked-ext",
     ]);
-    configure_cmd.arg(format!("--prefix={}", prefix.to_string_lossy()));
+    configure_cmd.arg(format!("--prefix={}", input.prefix.to_string_lossy()));
     configure_cmd.arg(format!("--with-destdir={}", install_dir.to_string_lossy()));
-    configure_cmd.arg(format!("--with-ext={}", enabled_extensions.join(",")));
+    configure_cmd.arg(format!("--with-ext={}", input.enabled_extentions.join(",")));
+    if let Some(baseruby) = &input.baseruby {
+        configure_cmd.arg(format!(
+            "--with-baseruby={}",
+            baseruby.path.to_string_lossy()
+        ));
+    }
     configure_cmd.arg("XLDFLAGS=-Xlinker --relocatable");
     configure_cmd.arg(format!("LDFLAGS={}", ldflags.join(" ")));
     configure_cmd.arg(format!("CFLAGS={}", cflags.join(" ")));
-    configure_cmd.arg(format!("CC={}/bin/clang", wasi_sdk));
-    configure_cmd.arg(format!("LD={}/bin/clang", wasi_sdk));
-    configure_cmd.arg(format!("AR={}/bin/llvm-ar", wasi_sdk));
-    configure_cmd.arg(format!("RANLIB={}/bin/llvm-ranlib", wasi_sdk));
+    let cc = toolchain.cc.to_string_lossy();
+    if let Some(compiler_cache) = &toolchain.compiler_cache {
+        configure_cmd.arg(format!(
+            "CC={} {}",
+            compiler_cache.program()?.to_string_lossy(),
+            cc
+        ));
+        configure_cmd.envs(compiler_cache.envs(&workspace.dir));
+    } else {
+        configure_cmd.arg(format!("CC={}", cc));
+    }
+    // configure links test programs through the compiler driver, not wasm-ld
+    configure_cmd.arg(format!("LD={}", cc));
+    configure_cmd.arg(format!("AR={}", toolchain.ar.to_string_lossy()));
+    configure_cmd.arg(format!("RANLIB={}", toolchain.ranlib.to_string_lossy()));
+    configure_cmd.args(input.profile.configure_args());
+    // put user-given arguments last to allow overriding the above
+    configure_cmd.args(input.extra_configure_args);
+    configure_cmd.envs(input.configure_env.iter().map(|(k, v)| (k, v)));
+    configure_cmd.envs(input.reproducible.iter().flat_map(Reproducible::envs));
 
     trace_command_exec(&configure_cmd, "./configure", Some(&build_dir));
-    let status = configure_cmd
-        .status()
+    let log_path = build_dir.join("configure.log");
+    let status = buildlog::run_logged(&mut configure_cmd, &log_path)
         .with_context(|| format!("failed to spawn {:?}", configure))?;
     if !status.success() {
-        bail!("configuration of cruby failed")
+        buildlog::report_failure(&log_path, Some(&build_dir.join("config.log")));
+        bail!(
+            "configuration of cruby failed, see {:?} for the full log",
+            relpath_for_display(&log_path)
+        )
     }
     Ok(())
 }
 
+/// Optimization and debuggability trade-off of the produced ruby.wasm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BuildProfile {
+    /// Unoptimized, assertion-enabled build with debuginfo
+    Debug,
+    #[default]
+    Release,
+    /// Optimized for code size
+    Size,
+}
+
+impl FromStr for BuildProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(BuildProfile::Debug),
+            "release" => Ok(BuildProfile::Release),
+            "size" => Ok(BuildProfile::Size),
+            other => bail!(
+                "unknown profile '{}', expected one of debug, release or size",
+                other
+            ),
+        }
+    }
+}
+
+impl BuildProfile {
+    /// `optflags` and `debugflags` variables for CRuby's configure
+    fn configure_args(&self) -> [&'static str; 2] {
+        match self {
+            BuildProfile::Debug => ["optflags=-O0", "debugflags=-g"],
+            BuildProfile::Release => ["optflags=-O2", "debugflags="],
+            BuildProfile::Size => ["optflags=-Oz", "debugflags="],
+        }
+    }
+
+    pub fn cflags(&self) -> &'static [&'static str] {
+        match self {
+            BuildProfile::Debug => &["-O0", "-g", "-DRUBY_DEBUG=1"],
+            BuildProfile::Release => &["-O2"],
+            BuildProfile::Size => &["-Oz"],
+        }
+    }
+
+    fn wasm_opt_level(&self) -> &'static str {
+        match self {
+            BuildProfile::Debug => "-O0",
+            BuildProfile::Release => "-O2",
+            BuildProfile::Size => "-Oz",
+        }
+    }
+
+    pub fn keeps_debuginfo(&self) -> bool {
+        matches!(self, BuildProfile::Debug)
+    }
+}
+
 #[derive(Hash)]
 pub struct CRubyBuildInput<'a> {
     pub source: BuildSource,
+    /// Absolute prefix where Ruby is placed in the guest filesystem, which is
+    /// what `RbConfig` reports (e.g. `/usr/local`)
+    pub prefix: PathBuf,
     pub asyncify_stack_size: usize,
     pub extra_cc_args: &'a [String],
+    /// Arguments passed through to ./configure
+    pub extra_configure_args: &'a [String],
+    /// Environment variables set for ./configure
+    pub configure_env: &'a [(String, String)],
+    pub transient_heap_total_size: Option<usize>,
+    /// Variables passed to make (e.g. `V=1`)
+    pub make_vars: &'a [(String, String)],
+    /// Overrides of commands in PATH during make, on top of the built-in
+    /// shadowing of wasm-opt
+    pub command_overrides: &'a [CommandOverride],
+    pub profile: BuildProfile,
     pub enabled_extentions: Vec<&'a str>,
+    /// C extensions of third-party gems built along with CRuby's ext/
+    pub gem_exts: Vec<GemExt>,
+    /// Host Ruby used for code generation during the build, checked against
+    /// the source on a cache miss. If `None`, configure looks up one by itself.
+    pub baseruby: Option<BaseRuby>,
+    /// Remap machine-specific paths and fix the build time
+    pub reproducible: Option<Reproducible>,
+}
+
+/// Paths remapped in a reproducible CRuby build. Build and install
+/// directories are hashed with the toolchain location, so they get fixed
+/// names of their own.
+fn cruby_path_remaps(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    build_dir: &Path,
+    install_dir: &Path,
+) -> Vec<(PathBuf, &'static str)> {
+    reproducible::path_remaps(
+        workspace,
+        toolchain,
+        &[
+            (build_dir, "/rbwasm/build/ruby"),
+            (install_dir, "/rbwasm/cache/ruby"),
+        ],
+    )
+}
+
+/// Copy a source tree into `dest` for a single build, which runs autogen and
+/// links gem extensions in it. Shared downloads and user checkouts are left
+/// untouched, and concurrent builds don't see each other's changes.
+fn copy_build_src(src_dir: &Path, dest: &Path) -> anyhow::Result<()> {
+    // left by an interrupted build
+    if dest.exists() {
+        std::fs::remove_dir_all(dest).with_context(|| format!("failed to remove {:?}", dest))?;
+    }
+    std::fs::create_dir_all(dest)?;
+    let mut cp = Command::new("cp");
+    // keep timestamps so that autotools don't regenerate files needlessly
+    cp.arg("-pR").arg(src_dir.join(".")).arg(dest);
+    trace_command_exec(&cp, "copy source", None);
+    let status = cp.status().context("failed to spawn cp")?;
+    if !status.success() {
+        bail!("failed to copy {:?} into {:?}", src_dir, dest)
+    }
+    Ok(())
+}
+
+/// Statistics are informational, so failing to query them doesn't fail the build
+fn compiler_cache_stats(compiler_cache: &CompilerCache) -> Option<CompilerCacheStats> {
+    match compiler_cache.stats() {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            log::warn!("failed to get {} statistics: {:#}", compiler_cache, e);
+            None
+        }
+    }
+}
+
+fn cruby_dirs(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    input: &CRubyBuildInput,
+) -> (PathBuf, PathBuf) {
+    workspace.hashed_dirs((&toolchain.identity, input), "ruby")
+}
+
+/// Whether `build_cruby` reuses a cached build for the input
+pub fn is_cruby_cached(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    input: &CRubyBuildInput,
+) -> bool {
+    cruby_dirs(workspace, toolchain, input).1.exists()
 }
 
 /// Build CRuby from a given source and returns installed path
@@ -293,48 +568,79 @@ pub fn build_cruby(
     input: &CRubyBuildInput,
 ) -> anyhow::Result<BuildResult> {
     log::info!("build cruby...");
-    const GUEST_RUBY_ROOT: &str = "/embd-root/ruby";
-    let guest_ruby_root: PathBuf = GUEST_RUBY_ROOT.into();
-    let (build_dir, install_dir) = workspace.hashed_dirs(input, "ruby");
+    if !input.prefix.is_absolute() {
+        bail!("guest ruby prefix must be absolute: {:?}", input.prefix);
+    }
+    ui_info!(
+        "enabled extensions: {}",
+        input.enabled_extentions.join(", ")
+    );
+    let (build_dir, install_dir) = cruby_dirs(workspace, toolchain, input);
     if install_dir.exists() {
         log::info!("cruby build cache found. skip building again");
+        workspace.record_phase("cruby build", Duration::ZERO, true);
         return Ok(BuildResult {
             install_dir,
+            build_dir,
             cached: true,
-            prefix: guest_ruby_root,
+            prefix: input.prefix.clone(),
+            compiler_cache_stats: None,
         });
     }
 
-    let src_dir = install_build_src(&input.source, &build_dir)?;
+    let shared_src_dir = install_build_src(workspace, &input.source)?;
+    if let Some(baseruby) = &input.baseruby {
+        baseruby.check_compatibility(&shared_src_dir)?;
+    }
+    let src_dir = build_dir.join("src");
+    workspace.phase("source copy", || copy_build_src(&shared_src_dir, &src_dir))?;
+    link_gem_exts(&input.gem_exts, &src_dir)?;
     let autogen_sh = src_dir.join("autogen.sh");
     let mut autogen_sh = Command::new(autogen_sh.as_path());
     trace_command_exec(&autogen_sh, "./autogen.sh", None);
 
-    let status = autogen_sh
-        .status()
-        .with_context(|| format!("failed to spawn {:?}", autogen_sh))?;
-    if !status.success() {
-        bail!("{:?} failed", autogen_sh)
-    }
+    workspace.phase("autogen", || {
+        let status = autogen_sh
+            .status()
+            .with_context(|| format!("failed to spawn {:?}", autogen_sh))?;
+        if !status.success() {
+            bail!("{:?} failed", autogen_sh)
+        }
+        Ok(())
+    })?;
 
-    configure_cruby(
-        toolchain,
-        src_dir,
-        &build_dir,
-        &install_dir,
-        &guest_ruby_root,
-        input.asyncify_stack_size,
-        input.enabled_extentions.clone(),
-        input.extra_cc_args,
-    )
-    .with_context(|| format!("configuration failed"))?;
+    let compiler_cache_stats_before = toolchain
+        .compiler_cache
+        .and_then(|compiler_cache| compiler_cache_stats(&compiler_cache));
+    workspace
+        .phase("configure", || {
+            configure_cruby(
+                workspace,
+                toolchain,
+                &src_dir,
+                &build_dir,
+                &install_dir,
+                input,
+            )
+        })
+        .context("configuration failed")?;
 
+    // wasm-opt doesn't support relocatable input but clang always apply wasm-opt whenever it's installed.
+    // However rbwasm uses --relocatable linker flag to concatenate all object files including native exts
+    // into single object file and link vfs object file after building CRuby.
+    // Therefore, override wasm-opt with fake binary to avoid breaking reloc section produced by --relocatable
+    let mut command_overrides = vec![CommandOverride::shadow("wasm-opt")];
+    command_overrides.extend(input.command_overrides.iter().cloned());
+    if is_debugging() {
+        for command_override in &command_overrides {
+            ui_info!("overriding command {}", command_override);
+        }
+    }
+
+    let make_log_path = build_dir.join("make.log");
+    let make_started = Instant::now();
     let status: anyhow::Result<ExitStatus> =
-        // wasm-opt doesn't support relocatable input but clang always apply wasm-opt whenever it's installed.
-        // However rbwasm uses --relocatable linker flag to concatenate all object files including native exts
-        // into single object file and link vfs object file after building CRuby.
-        // Therefore, override wasm-opt with fake binary to avoid breaking reloc section produced by --relocatable
-        workspace.with_overriding_command("wasm-opt", |fake_path| {
+        workspace.with_overriding_commands(&command_overrides, |fake_path| {
             let new_path = if let Some(current_path) = std::env::var_os("PATH") {
                 let mut current_paths = std::env::split_paths(&current_path).collect::<Vec<_>>();
                 current_paths.insert(0, fake_path.to_path_buf());
@@ -344,62 +650,249 @@ pub fn build_cruby(
             } else {
                 fake_path.as_os_str().to_os_string()
             };
-            let mut make = Command::new("make");
+            let mut make = toolchain.make.command();
             log::info!("setting PATH='{}'", new_path.to_string_lossy());
             make.current_dir(&build_dir)
                 .env("PATH", new_path)
+                .envs(
+                    toolchain
+                        .compiler_cache
+                        .iter()
+                        .flat_map(|compiler_cache| compiler_cache.envs(&workspace.dir)),
+                )
+                .envs(input.reproducible.iter().flat_map(Reproducible::envs))
                 .arg("install")
-                .arg(format!("-j{}", num_cpus::get()));
+                .args(input.make_vars.iter().map(|(k, v)| format!("{}={}", k, v)));
 
-            if !is_debugging() {
-                make.stdout(Stdio::null()).stderr(Stdio::null());
-            }
             trace_command_exec(&make, "make install", Some(&build_dir));
-            let status = make
-                .status()
+            let status = buildlog::run_logged(&mut make, &make_log_path)
                 .with_context(|| format!("failed to spawn make"))?;
             Ok(status)
         })?;
     let status = status?;
+    workspace.record_phase("make", make_started.elapsed(), false);
     if !status.success() {
-        bail!("make of cruby failed")
+        buildlog::report_failure(&make_log_path, None);
+        bail!(
+            "make of cruby failed, see {:?} for the full log",
+            relpath_for_display(&make_log_path)
+        )
+    }
+    if input.reproducible.is_some() {
+        let remaps = cruby_path_remaps(workspace, toolchain, &build_dir, &install_dir);
+        reproducible::remap_installed_files(&install_dir, &remaps)?;
     }
+    let compiler_cache_stats = match (toolchain.compiler_cache, compiler_cache_stats_before) {
+        (Some(compiler_cache), Some(before)) => {
+            compiler_cache_stats(&compiler_cache).map(|after| after.since(&before))
+        }
+        _ => None,
+    };
     Ok(BuildResult {
         install_dir,
+        build_dir,
         cached: false,
-        prefix: guest_ruby_root,
+        prefix: input.prefix.clone(),
+        compiler_cache_stats,
     })
 }
 
+/// Execution model of the produced module
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecModel {
+    /// WASI command whose `_start` runs ruby's main
+    #[default]
+    Command,
+    /// WASI reactor exporting the embedding API defined in reactor.c
+    Reactor,
+}
+
+impl ExecModel {
+    /// Exports every module of this model must have
+    pub fn exports(&self) -> &'static [&'static str] {
+        match self {
+            ExecModel::Command => &["_start"],
+            ExecModel::Reactor => &[
+                "_initialize",
+                "rbwasm_init",
+                "rbwasm_eval_string",
+                "rbwasm_eval_file",
+                "rbwasm_funcall",
+                "rbwasm_value_to_str",
+                "rbwasm_last_error",
+                "rbwasm_release",
+                "rbwasm_malloc",
+                "rbwasm_free",
+            ],
+        }
+    }
+}
+
+impl FromStr for ExecModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(ExecModel::Command),
+            "reactor" => Ok(ExecModel::Reactor),
+            other => bail!(
+                "unknown exec model: {} (expected command or reactor)",
+                other
+            ),
+        }
+    }
+}
+
+/// Compile the embedding API of reactor modules against the built CRuby
+pub fn build_reactor_shim(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    cruby: &BuildResult,
+    extra_cflags: &[String],
+) -> anyhow::Result<Vec<u8>> {
+    ui_info!("compiling reactor embedding API");
+    let mut cflags = ruby_include_flags(&cruby.installed_ruby_root())?;
+    cflags.extend(extra_cflags.iter().cloned());
+    compile_generated_c(
+        workspace,
+        toolchain,
+        "reactor.c",
+        include_str!("reactor.c"),
+        &cflags,
+    )
+}
+
+/// `-I` flags for the installed CRuby headers, which are split into common
+/// headers in `include/ruby-X.Y.Z` and `ruby/config.h` in its arch subdirectory
+fn ruby_include_flags(installed_ruby_root: &Path) -> anyhow::Result<Vec<String>> {
+    let include_dir = installed_ruby_root.join("include");
+    let entries = std::fs::read_dir(&include_dir)
+        .with_context(|| format!("failed to read dir: {:?}", include_dir))?;
+    for entry in entries {
+        let hdrdir = entry?.path();
+        if !hdrdir.join("ruby.h").exists() {
+            continue;
+        }
+        let mut flags = vec![format!("-I{}", hdrdir.to_string_lossy())];
+        for entry in std::fs::read_dir(&hdrdir)? {
+            let archhdrdir = entry?.path();
+            if archhdrdir.join("ruby/config.h").exists() {
+                flags.push(format!("-I{}", archhdrdir.to_string_lossy()));
+            }
+        }
+        return Ok(flags);
+    }
+    bail!("CRuby headers not found in {:?}", include_dir)
+}
+
+/// Compile a C source file into a wasm32-wasi object with the toolchain's cc
+pub fn compile_c(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    src_path: &Path,
+    cflags: &[String],
+) -> anyhow::Result<Vec<u8>> {
+    let name = src_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let obj_path = workspace.tempfile(&format!("{}.o", name), |_| Ok(()))?;
+    let mut cc = Command::new(&toolchain.cc);
+    cc.arg(toolchain.target_flag())
+        .arg(toolchain.sysroot_flag())
+        .args(cflags)
+        // temporary sources don't have .c extension
+        .args(["-c", "-x", "c"])
+        .arg(src_path)
+        .arg("-o")
+        .arg(&obj_path);
+    trace_command_exec(&cc, &format!("cc {}", name), None);
+    let status = cc
+        .status()
+        .with_context(|| format!("failed to spawn {:?}", toolchain.cc))?;
+    if !status.success() {
+        bail!("compilation of {:?} failed", src_path)
+    }
+    std::fs::read(&obj_path).with_context(|| format!("failed to read {:?}", obj_path))
+}
+
 pub struct LinkerInput<'a> {
     pub stack_size: usize,
+    pub memory: MemoryConfig,
+    pub exec_model: ExecModel,
+    /// Additional symbols to export
+    pub exports: &'a [String],
     pub raw_objects: Vec<(String, Vec<u8>)>,
+    /// Object files and static libraries on disk, linked after `raw_objects`
+    pub object_files: Vec<PathBuf>,
     pub extra_args: &'a [String],
 }
 
+impl LinkerInput<'_> {
+    /// Add a user-supplied file to link: an object file (`.o`), a static
+    /// library (`.a`) or a C source (`.c`) compiled with `cflags`
+    pub fn add_link_file(
+        &mut self,
+        workspace: &Workspace,
+        toolchain: &Toolchain,
+        path: &Path,
+        cflags: &[String],
+    ) -> anyhow::Result<()> {
+        if !path.is_file() {
+            bail!("file to link not found: {:?}", path);
+        }
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("o") | Some("a") => self.object_files.push(path.to_path_buf()),
+            Some("c") => {
+                let object = compile_c(workspace, toolchain, path, cflags)?;
+                let name = path.with_extension("o");
+                let name = name.file_name().unwrap().to_string_lossy().to_string();
+                self.raw_objects.push((name, object));
+            }
+            _ => bail!(
+                "unsupported file to link: {:?} (expected .o, .a or .c)",
+                path
+            ),
+        }
+        Ok(())
+    }
+}
+
 pub fn link_executable(
-    workspace: &mut Workspace,
+    workspace: &Workspace,
     toolchain: &Toolchain,
     cruby: &BuildResult,
     input: &LinkerInput,
     output: &Path,
 ) -> anyhow::Result<()> {
     log::info!("link single ruby binary");
-    let wasm_ld = toolchain.wasi_sdk.join("bin/wasm-ld");
-    let mut link = Command::new(wasm_ld);
-    link.arg(
-        cruby
-            .install_dir
-            .join(cruby.prefix.strip_prefix("/")?)
-            .join("bin/ruby"),
-    );
-    link.args(["--stack-first", "-z"]);
+    let mut link = Command::new(&toolchain.ld);
+    link.arg(cruby.installed_ruby_root().join("bin/ruby"));
+    link.arg("-z");
     link.arg(format!("stack-size={}", input.stack_size));
+    link.args(input.memory.linker_args());
+    if input.exec_model == ExecModel::Reactor {
+        // crt1-reactor.o defines and exports `_initialize` running ctors, and
+        // reactor.c marks its functions with export_name, so they are
+        // exported and kept without --export
+        let crt1_reactor = toolchain.sysroot.join("lib/wasm32-wasi/crt1-reactor.o");
+        if !crt1_reactor.is_file() {
+            bail!("wasi sysroot has no crt1-reactor.o: {:?}", crt1_reactor);
+        }
+        link.arg("--no-entry").arg(crt1_reactor);
+    }
+    // wasm-ld fails if any of them is not defined
+    link.args(
+        input
+            .exports
+            .iter()
+            .map(|name| format!("--export={}", name)),
+    );
     link.arg("-o");
     link.arg(output);
     link.args(input.extra_args);
 
-    fn link_inner(mut link: Command, workspace: &mut Workspace) -> anyhow::Result<ExitStatus> {
+    fn link_inner(mut link: Command, workspace: &Workspace) -> anyhow::Result<ExitStatus> {
         let libvfs_path = workspace.tempfile("libwasi_vfs.a", |libvfs| {
             libvfs.write_all(std::include_bytes!(std::concat!(
                 std::env!("OUT_DIR"),
@@ -423,6 +916,7 @@ pub fn link_executable(
         })?;
         link.arg(objfile_path);
     }
+    link.args(&input.object_files);
     let status = link_inner(link, workspace)?;
 
     if !status.success() {
@@ -433,7 +927,9 @@ pub fn link_executable(
 
 pub fn asyncify_executable(
     toolchain: &Toolchain,
+    profile: BuildProfile,
     with_debuginfo: bool,
+    pass_args: &[String],
     input: &Path,
     output: &Path,
 ) -> anyhow::Result<()> {
@@ -441,11 +937,12 @@ pub fn asyncify_executable(
     let mut wasm_opt = Command::new(&toolchain.wasm_opt);
     wasm_opt.arg(&input);
     wasm_opt.arg("--asyncify");
-    wasm_opt.arg("-O");
-    if with_debuginfo {
+    wasm_opt.arg(profile.wasm_opt_level());
+    if with_debuginfo || profile.keeps_debuginfo() {
         wasm_opt.arg("-g");
     }
-    wasm_opt.arg("--pass-arg=asyncify-ignore-imports");
+    // from AsyncifyConfig::pass_args
+    wasm_opt.args(pass_args);
     wasm_opt.arg("-o");
     wasm_opt.arg(&output);
     trace_command_exec(&wasm_opt, "asyncify", None);
@@ -464,6 +961,21 @@ pub struct MkfsInput<'a> {
     pub map_paths: Vec<(PathBuf, PathBuf)>,
 }
 
+impl MkfsInput<'_> {
+    /// Map paths as `(guest, host)` with `@ruby_root` expanded, sorted so
+    /// that the VFS image doesn't depend on the order they were collected in
+    pub fn expanded_map_paths(&self) -> Vec<(PathBuf, PathBuf)> {
+        let mut map_paths = self
+            .map_paths
+            .iter()
+            .cloned()
+            .map(|map| expand_map_dir(map, self.host_ruby_root, self.guest_ruby_root))
+            .collect::<Vec<_>>();
+        map_paths.sort();
+        map_paths
+    }
+}
+
 fn expand_map_dir(
     map_dir: (PathBuf, PathBuf),
     host_ruby_root: &Path,
@@ -529,13 +1041,10 @@ pub fn mkfs(
     workspace: &Workspace,
     toolchain: &Toolchain,
     input: MkfsInput,
+    extra_cflags: &[String],
 ) -> anyhow::Result<Vec<u8>> {
     ui_info!("generating vfs image");
-    let map_paths = input
-        .map_paths
-        .into_iter()
-        .map(|map| expand_map_dir(map, input.host_ruby_root, input.guest_ruby_root));
-    let fs_c_src = wasi_vfs_mkfs::generate_c_source(map_paths)?;
+    let fs_c_src = wasi_vfs_mkfs::generate_c_source(input.expanded_map_paths().into_iter())?;
     if is_debugging() {
         let fs_c = workspace.temporary_dir().join("fs.c");
         ui_info!("exporting vfs intermediate source to {:?}", &fs_c);
@@ -547,15 +1056,14 @@ pub fn mkfs(
             );
         }
     }
-    let clang = toolchain.wasi_sdk.join("bin/clang");
-    let object = wasi_vfs_mkfs::generate_obj(&fs_c_src, &clang.to_string_lossy())?;
-    Ok(object)
+    compile_generated_c(workspace, toolchain, "fs.c", &fs_c_src, extra_cflags)
 }
 
 pub fn mkargs(
     workspace: &Workspace,
     toolchain: &Toolchain,
     args: &[String],
+    extra_cflags: &[String],
 ) -> anyhow::Result<Vec<u8>> {
     ui_info!("generating preset arguments data");
     let preset_args_c_src = wasi_preset_args::generate_c_source("ruby.wasm", args)?;
@@ -573,9 +1081,36 @@ pub fn mkargs(
             );
         }
     }
-    let clang = toolchain.wasi_sdk.join("bin/clang");
-    let object = wasi_preset_args::generate_obj(&preset_args_c_src, &clang.to_string_lossy())?;
-    Ok(object)
+    compile_generated_c(
+        workspace,
+        toolchain,
+        "preset-args.c",
+        &preset_args_c_src,
+        extra_cflags,
+    )
+}
+
+/// Compile C source held in memory with the same target and sysroot flags as
+/// everything else, which the generators' own `generate_obj` doesn't pass
+fn compile_generated_c(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    name: &str,
+    src: &str,
+    extra_cflags: &[String],
+) -> anyhow::Result<Vec<u8>> {
+    let src_path = workspace.tempfile(name, |file| {
+        file.write_all(src.as_bytes())?;
+        Ok(())
+    })?;
+    let mut cflags = extra_cflags.to_vec();
+    // record the source under its own name rather than the random temporary one
+    cflags.push(format!(
+        "-ffile-prefix-map={}={}",
+        src_path.to_string_lossy(),
+        name
+    ));
+    compile_c(workspace, toolchain, &src_path, &cflags)
 }
 
 pub fn run_build_hook(build_hook: &str, host_ruby_root: &Path) -> anyhow::Result<()> {
@@ -597,6 +1132,10 @@ fn extract_tarball<R: std::io::Read>(src: &mut R, dest: &Path) -> anyhow::Result
         .stdin(Stdio::piped())
         .spawn()?;
     std::io::copy(src, &mut tar.stdin.take().unwrap())?;
+    let status = tar.wait()?;
+    if !status.success() {
+        bail!("failed to extract tarball into {:?}", dest)
+    }
     Ok(())
 }
 
@@ -616,7 +1155,10 @@ pub(crate) fn is_debugging() -> bool {
 mod tests {
     use std::path::Path;
 
-    use crate::expand_map_dir;
+    use crate::{
+        expand_map_dir, ruby_include_flags, toolchain::Toolchain, ExecModel, LinkerInput,
+        MkfsInput, Workspace,
+    };
 
     #[test]
     fn test_expand_map_dir() {
@@ -628,4 +1170,84 @@ mod tests {
         assert_eq!(host.to_string_lossy(), "/install/prefix/lib/gems");
         assert_eq!(guest.to_string_lossy(), "/gems");
     }
+
+    #[test]
+    fn test_expanded_map_paths_order() {
+        let input = MkfsInput {
+            host_ruby_root: Path::new("/install/prefix"),
+            guest_ruby_root: Path::new("/prefix"),
+            map_paths: vec![
+                ("/srv".into(), "/home/user/srv".into()),
+                ("@ruby_root/lib".into(), "@ruby_root/lib".into()),
+                ("/app".into(), "/home/user/app".into()),
+            ],
+        };
+        let guests = input
+            .expanded_map_paths()
+            .into_iter()
+            .map(|(guest, _)| guest.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(guests, vec!["/app", "/prefix/lib", "/srv"]);
+    }
+
+    #[test]
+    fn test_ruby_include_flags() {
+        let root = tempfile::tempdir().unwrap();
+        let hdrdir = root.path().join("include/ruby-3.1.0");
+        std::fs::create_dir_all(hdrdir.join("wasm32-wasi/ruby")).unwrap();
+        std::fs::write(hdrdir.join("ruby.h"), "").unwrap();
+        std::fs::write(hdrdir.join("wasm32-wasi/ruby/config.h"), "").unwrap();
+        let flags = ruby_include_flags(root.path()).unwrap();
+        assert_eq!(
+            flags,
+            vec![
+                format!("-I{}", hdrdir.to_string_lossy()),
+                format!("-I{}", hdrdir.join("wasm32-wasi").to_string_lossy())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reactor_shim_exports() {
+        // everything but `_initialize` from crt1-reactor.o
+        let pattern = regex::Regex::new(r"(?m)^RBWASM_EXPORT\((\w+)\)$").unwrap();
+        let mut shim_exports = pattern
+            .captures_iter(include_str!("reactor.c"))
+            .map(|captures| captures[1].to_string())
+            .collect::<Vec<_>>();
+        shim_exports.insert(0, String::from("_initialize"));
+        assert_eq!(shim_exports, ExecModel::Reactor.exports());
+    }
+
+    #[test]
+    fn test_add_link_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = Workspace::create(dir.path().join("workspace"), false).unwrap();
+        let toolchain =
+            Toolchain::from_wasi_sdk(Path::new("fake-wasi-sdk"), "fake-wasm-opt".into());
+        let mut input = LinkerInput {
+            stack_size: 0,
+            memory: Default::default(),
+            exec_model: ExecModel::Command,
+            exports: &[],
+            raw_objects: vec![],
+            object_files: vec![],
+            extra_args: &[],
+        };
+        let archive = dir.path().join("libbinding.a");
+        std::fs::write(&archive, "").unwrap();
+        input
+            .add_link_file(&workspace, &toolchain, &archive, &[])
+            .unwrap();
+        assert_eq!(input.object_files, vec![archive]);
+
+        let script = dir.path().join("binding.rb");
+        std::fs::write(&script, "").unwrap();
+        assert!(input
+            .add_link_file(&workspace, &toolchain, &script, &[])
+            .is_err());
+        assert!(input
+            .add_link_file(&workspace, &toolchain, &dir.path().join("missing.o"), &[])
+            .is_err());
+    }
 }
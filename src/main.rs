@@ -1,10 +1,25 @@
-use anyhow::bail;
+use anyhow::{bail, Context};
 use rbwasm::{
-    asyncify_executable, build_cruby, builtin_map_paths, link_executable, mkargs, mkfs,
-    run_build_hook, toolchain, BuildSource, CRubyBuildInput, LinkerInput, MkfsInput, Workspace,
-    DEFAULT_ENABLED_EXTENSIONS,
+    asyncify::AsyncifyConfig,
+    build_cruby, build_reactor_shim, builtin_map_paths,
+    config::{Config, Variant, DEFAULT_CONFIG_FILE},
+    debuginfo::{self, Symbolizer},
+    existing_build_src,
+    ext::{self, ExtSelection},
+    gem::Gem,
+    install_build_src, is_cruby_cached, link_executable,
+    memory::MemoryConfig,
+    mkargs, mkfs,
+    overrides::CommandOverride,
+    postlink::{self, PostLinkOptions, PostLinkStep},
+    reproducible::{self, Reproducible},
+    run_build_hook,
+    size_report::{self, SizeReport},
+    summary::BuildSummary,
+    toolchain::{self, BaseRuby, CompilerCache, CompilerCacheStats, Make, ToolchainOverrides},
+    wasm, BuildProfile, BuildSource, CRubyBuildInput, ExecModel, LinkerInput, MkfsInput, Workspace,
 };
-use std::path::PathBuf;
+use std::{collections::HashMap, ffi::OsString, path::PathBuf, process::Command};
 use structopt::StructOpt;
 
 fn parse_map_dirs(s: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
@@ -17,6 +32,40 @@ fn parse_map_dirs(s: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
     Ok((parts[0].into(), parts[1].into()))
 }
 
+fn parse_key_value(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => bail!("must be in KEY=VALUE form"),
+    }
+}
+
+fn parse_jobs(s: &str) -> anyhow::Result<usize> {
+    match s.parse()? {
+        0 => bail!("must be at least 1"),
+        jobs => Ok(jobs),
+    }
+}
+
+fn parse_name_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Names given directly followed by the ones in list files
+fn read_name_lists(names: &[String], files: &[PathBuf]) -> anyhow::Result<Vec<String>> {
+    let mut names = names.to_vec();
+    for file in files {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("failed to read list file {:?}", file))?;
+        names.extend(parse_name_list(&content));
+    }
+    Ok(names)
+}
+
 fn parse_build_src(s: &str) -> anyhow::Result<BuildSource> {
     let mut kind_and_rests = s.split(":");
     let kind = if let Some(kind) = kind_and_rests.next() {
@@ -62,8 +111,31 @@ struct Opt {
     #[structopt(long, default_value = "6144")]
     asyncify_stack_size: usize,
 
+    /// Initial linear memory in bytes, a multiple of 64KiB. Must fit the
+    /// stack and data.
+    #[structopt(long)]
+    initial_memory: Option<u64>,
+
+    /// Maximum linear memory in bytes, a multiple of 64KiB
+    #[structopt(long)]
+    max_memory: Option<u64>,
+
+    /// Import linear memory from the host instead of defining it
+    #[structopt(long)]
+    import_memory: bool,
+
+    /// Export linear memory even when it's imported
+    #[structopt(long)]
+    export_memory: bool,
+
+    /// Address where data starts. The stack is placed after data instead of
+    /// first in memory
+    #[structopt(long)]
+    global_base: Option<u64>,
+
+    /// Output file. Not allowed when variants are given in the config file
     #[structopt(short)]
-    output: PathBuf,
+    output: Option<PathBuf>,
 
     #[structopt(long)]
     save_temps: bool,
@@ -71,15 +143,105 @@ struct Opt {
     #[structopt(long)]
     no_builtin_files: bool,
 
-    #[structopt(long)]
+    /// Comma-separated list of extensions replacing the defaults
+    #[structopt(long, conflicts_with = "exts")]
     enabled_exts: Option<String>,
 
+    /// Extension selection relative to a preset (minimal, default or full),
+    /// e.g. "+openssl,-ripper" or "minimal,+json"
+    #[structopt(long)]
+    exts: Option<ExtSelection>,
+
+    /// Module kind: command (runs ruby's main from _start) or reactor
+    /// (exports an embedding API such as rbwasm_init and rbwasm_eval_string)
+    #[structopt(long, default_value = "command")]
+    exec_model: ExecModel,
+
     #[structopt(short = "g")]
     with_debuginfo: bool,
 
+    /// Import which may unwind the stack, as MODULE.NAME. By default no import
+    /// is assumed to unwind.
+    #[structopt(
+        long = "asyncify-import",
+        number_of_values = 1,
+        value_name = "MODULE.NAME"
+    )]
+    asyncify_imports: Vec<String>,
+
+    /// File listing asyncify imports, one per line
+    #[structopt(long, number_of_values = 1)]
+    asyncify_imports_file: Vec<PathBuf>,
+
+    /// Function to instrument with asyncify, excluding all others. `*` works
+    /// as a wildcard.
+    #[structopt(long = "asyncify-only", number_of_values = 1, value_name = "FUNCTION")]
+    asyncify_onlylist: Vec<String>,
+
+    /// File listing functions for --asyncify-only, one per line
+    #[structopt(long, number_of_values = 1)]
+    asyncify_onlylist_file: Vec<PathBuf>,
+
+    /// Function never to instrument with asyncify
+    #[structopt(
+        long = "asyncify-remove",
+        number_of_values = 1,
+        value_name = "FUNCTION"
+    )]
+    asyncify_removelist: Vec<String>,
+
+    /// File listing functions for --asyncify-remove, one per line
+    #[structopt(long, number_of_values = 1)]
+    asyncify_removelist_file: Vec<PathBuf>,
+
+    /// Function to instrument with asyncify in addition to the analyzed ones
+    #[structopt(long = "asyncify-add", number_of_values = 1, value_name = "FUNCTION")]
+    asyncify_addlist: Vec<String>,
+
+    /// File listing functions for --asyncify-add, one per line
+    #[structopt(long, number_of_values = 1)]
+    asyncify_addlist_file: Vec<PathBuf>,
+
+    /// Assume indirect calls may unwind, for modules linked with others later
+    #[structopt(long)]
+    asyncify_relocatable: bool,
+
+    /// Import the output may have besides WASI, as MODULE or MODULE.NAME.
+    /// Any other import fails the build, except functions left undefined by
+    /// --link files, asyncify imports and the memory with --import-memory.
+    #[structopt(long = "allow-import", number_of_values = 1, value_name = "IMPORT")]
+    allowed_imports: Vec<String>,
+
+    /// Remap machine-specific paths in debug info and RbConfig and build
+    /// CRuby with SOURCE_DATE_EPOCH, so that the same inputs produce the same
+    /// bytes on any machine. `rbwasm verify-reproducible <build options>`
+    /// checks it by building twice.
+    #[structopt(long)]
+    reproducible: bool,
+
+    /// Build with DWARF and move it to <output stem>.debug.wasm, leaving the
+    /// output stripped. Both files get the same build id.
+    #[structopt(long)]
+    split_debuginfo: bool,
+
+    /// Gem source directory whose C extensions are statically linked and
+    /// whose lib/ is mapped into the VFS
+    #[structopt(long = "gem", number_of_values = 1, value_name = "GEM_DIR")]
+    gem_dirs: Vec<PathBuf>,
+
+    /// Build profile: debug, release or size
+    #[structopt(long, default_value = "release")]
+    profile: BuildProfile,
+
     #[structopt(long, default_value = "github:kateinoigakukun/ruby@9bcc194dc3c12f017a41b6287f85b58f2c487bf8", parse(try_from_str = parse_build_src))]
     cruby_src: BuildSource,
 
+    /// Prefix of Ruby in the guest filesystem, reported by RbConfig. The
+    /// default is kept for existing scripts; pass /usr/local for a
+    /// conventional layout.
+    #[structopt(long, default_value = "/embd-root/ruby")]
+    guest_ruby_prefix: PathBuf,
+
     #[structopt(long)]
     build_hook: Option<String>,
 
@@ -89,13 +251,325 @@ struct Opt {
     #[structopt(long = "Xlinker", number_of_values = 1)]
     extra_linker_args: Vec<String>,
 
+    /// Argument appended to wasm-opt of the asyncify step, e.g. -Oz or
+    /// --strip-producers. Other passes can run as post-link steps in the
+    /// config file.
+    #[structopt(long = "Xwasm-opt", number_of_values = 1)]
+    extra_wasm_opt_args: Vec<String>,
+
+    /// Extra argument passed to CRuby's ./configure (e.g. --with-gmp or optflags=-O3)
+    #[structopt(long = "Xconfigure", number_of_values = 1, allow_hyphen_values = true)]
+    extra_configure_args: Vec<String>,
+
+    /// Environment variable set while running CRuby's ./configure
+    #[structopt(long = "configure-env", number_of_values = 1, value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+    configure_env: Vec<(String, String)>,
+
+    /// Number of parallel make jobs. Defaults to the jobserver of an outer
+    /// make if any, or the number of CPUs
+    #[structopt(short, long, parse(try_from_str = parse_jobs))]
+    jobs: Option<usize>,
+
+    /// make program to use (e.g. gmake)
+    #[structopt(long, default_value = "make")]
+    make: PathBuf,
+
+    /// Variable passed to make
+    #[structopt(long = "make-var", number_of_values = 1, value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+    make_vars: Vec<(String, String)>,
+
+    /// Object file (.o), static library (.a) or C source (.c) linked into
+    /// ruby.wasm. C sources are compiled with the Xcc flags.
+    #[structopt(long = "link", number_of_values = 1, value_name = "FILE")]
+    link_files: Vec<PathBuf>,
+
+    /// Symbol to export from the module, kept alive through wasm-opt
+    #[structopt(long = "export", number_of_values = 1, value_name = "SYMBOL")]
+    exports: Vec<String>,
+
+    /// File listing symbols to export, one per line. Lines starting with #
+    /// are ignored.
+    #[structopt(long = "export-file", number_of_values = 1)]
+    export_files: Vec<PathBuf>,
+
+    /// Print a breakdown of the output size by section, function, VFS
+    /// entry and extension. `rbwasm size <module>` shows it for an existing
+    /// module.
+    #[structopt(long)]
+    size_report: bool,
+
+    /// Write the size breakdown as JSON to <output>.size.json
+    #[structopt(long)]
+    size_report_json: bool,
+
+    /// Override a command in PATH while building CRuby: NAME=shadow,
+    /// NAME=replace:PATH or NAME=wrap:PATH
+    #[structopt(
+        long = "override-command",
+        number_of_values = 1,
+        value_name = "NAME=ACTION"
+    )]
+    command_overrides: Vec<CommandOverride>,
+
+    /// Config file. Defaults to rbwasm.toml in the current directory if exists
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Size of the transient heap in bytes
+    #[structopt(long, env = "TRANSIENT_HEAP_TOTAL_SIZE")]
+    transient_heap_total_size: Option<usize>,
+
+    /// Compiler cache wrapping the C compiler while building CRuby: ccache or sccache
+    #[structopt(long)]
+    compiler_cache: Option<CompilerCache>,
+
+    /// Use an existing wasi-sdk installation instead of downloading it
+    #[structopt(long)]
+    wasi_sdk: Option<PathBuf>,
+
+    /// C compiler targeting wasm32-wasi (e.g. system clang)
+    #[structopt(long)]
+    cc: Option<PathBuf>,
+
+    /// wasm-ld used to link the final module
+    #[structopt(long)]
+    ld: Option<PathBuf>,
+
+    #[structopt(long)]
+    ar: Option<PathBuf>,
+
+    #[structopt(long)]
+    ranlib: Option<PathBuf>,
+
+    /// wasi-libc sysroot (e.g. /usr/share/wasi-sysroot)
+    #[structopt(long)]
+    sysroot: Option<PathBuf>,
+
+    /// Host ruby used to build CRuby. Defaults to $BASERUBY or ruby in PATH.
+    /// Its version is a part of the CRuby build cache key.
+    #[structopt(long)]
+    baseruby: Option<PathBuf>,
+
+    /// Leave looking up a host ruby to CRuby's configure instead of checking
+    /// one before building. Cached builds are then reused with any host ruby.
+    #[structopt(long, conflicts_with = "baseruby")]
+    no_baseruby_check: bool,
+
     #[structopt(name = "PRESET_ARGS", last = true)]
     preset_args: Vec<String>,
 }
 
+/// Number of the largest entries listed in size reports
+const SIZE_REPORT_TOP: usize = 20;
+
+/// `rbwasm size`: size breakdown of an existing module
+#[derive(StructOpt)]
+#[structopt(name = "rbwasm size")]
+struct SizeOpt {
+    module: PathBuf,
+
+    /// Print the report as JSON to stdout
+    #[structopt(long)]
+    json: bool,
+
+    /// Number of the largest functions to list
+    #[structopt(long, default_value = "20")]
+    top: usize,
+}
+
+fn size_main(opt: SizeOpt) -> anyhow::Result<()> {
+    let report = SizeReport::from_module(&opt.module, opt.top)?;
+    if opt.json {
+        println!("{}", report.to_json()?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
+
+/// `rbwasm inspect`: imports and exports of a module
+#[derive(StructOpt)]
+#[structopt(name = "rbwasm inspect")]
+struct InspectOpt {
+    module: PathBuf,
+
+    /// Also validate the module
+    #[structopt(long)]
+    validate: bool,
+
+    /// Import allowed besides WASI, as MODULE or MODULE.NAME. Unexpected
+    /// imports fail the command when any is given.
+    #[structopt(long = "allow-import", number_of_values = 1, value_name = "IMPORT")]
+    allowed_imports: Vec<String>,
+}
+
+fn inspect_main(opt: InspectOpt) -> anyhow::Result<()> {
+    let module =
+        std::fs::read(&opt.module).with_context(|| format!("failed to read {:?}", opt.module))?;
+    let interface = wasm::module_interface(&module)
+        .with_context(|| format!("failed to parse {:?}", opt.module))?;
+    interface.print();
+    if opt.validate {
+        wasm::validate(&opt.module)?;
+    }
+    if !opt.allowed_imports.is_empty() {
+        wasm::verify_imports(&opt.module, &opt.allowed_imports)?;
+    }
+    Ok(())
+}
+
+/// `rbwasm symbolize`: locations of offsets in a stack trace of a module built
+/// with `--split-debuginfo`
+#[derive(StructOpt)]
+#[structopt(name = "rbwasm symbolize")]
+struct SymbolizeOpt {
+    /// Debug file. Defaults to the one named by the module.
+    #[structopt(long)]
+    debug_file: Option<PathBuf>,
+
+    /// Stripped module the trace comes from, checked to have the same build
+    /// id as the debug file
+    #[structopt(long)]
+    module: Option<PathBuf>,
+
+    /// File with the stack trace. Reads stdin if omitted.
+    trace: Option<PathBuf>,
+}
+
+fn symbolize_main(opt: SymbolizeOpt) -> anyhow::Result<()> {
+    let module = opt
+        .module
+        .as_ref()
+        .map(|path| std::fs::read(path).with_context(|| format!("failed to read {:?}", path)))
+        .transpose()?;
+    let debug_file = match (&opt.debug_file, &opt.module, &module) {
+        (Some(debug_file), _, _) => debug_file.clone(),
+        (None, Some(path), Some(module)) => match debuginfo::external_debug_info(module)? {
+            Some(name) => path.with_file_name(name),
+            None => bail!("{:?} doesn't name a debug file; pass --debug-file", path),
+        },
+        _ => bail!("--debug-file or --module is required"),
+    };
+    let debug_module =
+        std::fs::read(&debug_file).with_context(|| format!("failed to read {:?}", debug_file))?;
+    let symbolizer = Symbolizer::load(&debug_module)
+        .with_context(|| format!("failed to load debug info from {:?}", debug_file))?;
+    if let Some(module) = &module {
+        let build_id = debuginfo::build_id(module)?;
+        if build_id.as_deref() != symbolizer.build_id() {
+            bail!(
+                "build id of {:?} doesn't match the debug file {:?}",
+                opt.module.unwrap(),
+                debug_file
+            );
+        }
+    }
+    let trace = match &opt.trace {
+        Some(path) => {
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?
+        }
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    print!("{}", symbolizer.symbolize_trace(&trace));
+    Ok(())
+}
+
+/// Build arguments without `-o` and `--reproducible`, which
+/// `verify-reproducible` sets by itself
+fn strip_verify_reproducible_args(args: &[OsString]) -> Vec<OsString> {
+    let mut stripped = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            stripped.push(arg.clone());
+            stripped.extend(args.cloned());
+            break;
+        }
+        if arg == "-o" {
+            args.next();
+            continue;
+        }
+        let is_output = arg.to_str().is_some_and(|arg| arg.starts_with("-o"));
+        if is_output || arg == "--reproducible" {
+            continue;
+        }
+        stripped.push(arg.clone());
+    }
+    stripped
+}
+
+/// `rbwasm verify-reproducible`: build twice in separate workspaces with
+/// `--reproducible` and compare the outputs
+fn verify_reproducible_main(build_args: &[OsString]) -> anyhow::Result<()> {
+    let opt = Opt::from_iter(std::iter::once(OsString::from("rbwasm")).chain(build_args.to_vec()));
+    let output = match &opt.output {
+        Some(output) => output.clone(),
+        None => bail!("verify-reproducible requires -o"),
+    };
+    let file_name = output
+        .file_name()
+        .with_context(|| format!("invalid output path {:?}", output))?;
+    let build_args = strip_verify_reproducible_args(build_args);
+    let exe = std::env::current_exe()?;
+    let temp_dir = tempfile::tempdir()?;
+    let mut outputs = vec![];
+    for run in ["first", "second"] {
+        let run_dir = temp_dir.path().join(run);
+        let run_output = run_dir.join(file_name);
+        std::fs::create_dir_all(&run_dir)?;
+        eprintln!("verify-reproducible: {} build in {:?}", run, run_dir);
+        let status = Command::new(&exe)
+            .env("RBWASM_ROOT", run_dir.join("workspace"))
+            .arg("--reproducible")
+            .arg("-o")
+            .arg(&run_output)
+            .args(&build_args)
+            .status()
+            .with_context(|| format!("failed to spawn {:?}", exe))?;
+        if !status.success() {
+            bail!("{} build failed", run);
+        }
+        outputs.push(run_output);
+    }
+    let mut compared = vec![(outputs[0].clone(), outputs[1].clone())];
+    if opt.split_debuginfo {
+        compared.push((
+            debuginfo::debug_file_path(&outputs[0]),
+            debuginfo::debug_file_path(&outputs[1]),
+        ));
+    }
+    for (first, second) in &compared {
+        reproducible::compare_outputs(first, second).context("build is not reproducible")?;
+    }
+    for (first, _) in &compared {
+        let dest = output.with_file_name(first.file_name().unwrap());
+        std::fs::copy(first, &dest).with_context(|| format!("failed to write {:?}", dest))?;
+    }
+    eprintln!(
+        "verify-reproducible: both builds produced identical {:?}",
+        output
+    );
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     env_logger::init();
-    let opt = Opt::from_args();
+    let args = std::env::args_os().collect::<Vec<_>>();
+    match args.get(1).and_then(|arg| arg.to_str()) {
+        Some("size") => return size_main(SizeOpt::from_iter(&args[1..])),
+        Some("symbolize") => return symbolize_main(SymbolizeOpt::from_iter(&args[1..])),
+        Some("inspect") => return inspect_main(InspectOpt::from_iter(&args[1..])),
+        Some("verify-reproducible") => return verify_reproducible_main(&args[2..]),
+        _ => {}
+    }
+    let opt = Opt::from_iter(args);
+    let config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None if PathBuf::from(DEFAULT_CONFIG_FILE).exists() => {
+            Config::load(DEFAULT_CONFIG_FILE.as_ref())?
+        }
+        None => Config::default(),
+    };
     let workspace_dir: PathBuf = std::env::var("RBWASM_ROOT")
         .unwrap_or(String::from(".rbwasm"))
         .into();
@@ -103,74 +577,479 @@ fn main() -> anyhow::Result<()> {
         log::debug!("workspace dir doesn't exist. create {:?}", workspace_dir);
         std::fs::create_dir_all(&workspace_dir)?;
     }
-    let mut workspace = Workspace::create(workspace_dir.canonicalize()?, opt.save_temps)?;
-    let toolchain = toolchain::install_build_toolchain(&workspace)?;
-    let enabled_extentions = if let Some(exts) = &opt.enabled_exts {
-        exts.split(",").collect::<Vec<_>>()
-    } else {
-        DEFAULT_ENABLED_EXTENSIONS.to_vec()
-    };
-    let cruby = build_cruby(
+    let workspace = Workspace::create(workspace_dir.canonicalize()?, opt.save_temps)?;
+    let mut toolchain = toolchain::install_build_toolchain(
         &workspace,
-        &toolchain,
-        &CRubyBuildInput {
-            source: opt.cruby_src,
-            asyncify_stack_size: opt.asyncify_stack_size,
-            extra_cc_args: &opt.extra_cc_args,
-            enabled_extentions,
+        ToolchainOverrides {
+            wasi_sdk: opt.wasi_sdk,
+            cc: opt.cc,
+            ld: opt.ld,
+            ar: opt.ar,
+            ranlib: opt.ranlib,
+            sysroot: opt.sysroot,
         },
     )?;
+    toolchain.make = Make {
+        program: opt.make,
+        jobs: opt.jobs,
+    };
+    toolchain.compiler_cache = opt.compiler_cache;
+    let baseruby = if opt.no_baseruby_check {
+        None
+    } else {
+        Some(BaseRuby::find(opt.baseruby.clone())?)
+    };
+    let reproducible = if opt.reproducible {
+        Some(Reproducible::from_env()?)
+    } else {
+        None
+    };
+    // for sources compiled outside of the CRuby build
+    let reproducible_cflags = if reproducible.is_some() {
+        reproducible::prefix_map_flags(&reproducible::path_remaps(&workspace, &toolchain, &[]))
+    } else {
+        vec![]
+    };
+    let mut command_overrides = config.command_overrides.clone();
+    command_overrides.extend(opt.command_overrides);
+    let exports = read_name_lists(&opt.exports, &opt.export_files)?;
+    let asyncify = AsyncifyConfig {
+        imports: read_name_lists(&opt.asyncify_imports, &opt.asyncify_imports_file)?,
+        onlylist: read_name_lists(&opt.asyncify_onlylist, &opt.asyncify_onlylist_file)?,
+        removelist: read_name_lists(&opt.asyncify_removelist, &opt.asyncify_removelist_file)?,
+        addlist: read_name_lists(&opt.asyncify_addlist, &opt.asyncify_addlist_file)?,
+        relocatable: opt.asyncify_relocatable,
+    };
+    asyncify.validate()?;
+    let asyncify_pass_args = asyncify.pass_args(&workspace)?;
+    let gems = opt
+        .gem_dirs
+        .iter()
+        .map(|dir| Gem::discover(dir))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // listing ext/ needs the source, which cached CRuby builds don't
+    let mut available_extensions = match existing_build_src(&workspace, &opt.cruby_src) {
+        Some(src_dir) => Some(ext::available_extensions(&src_dir)?),
+        None => None,
+    };
+    let base_variant = Variant {
+        name: String::from("default"),
+        output: opt.output.clone().unwrap_or_default(),
+        exts: if let Some(exts) = &opt.enabled_exts {
+            ExtSelection::exactly(&exts.split(",").collect::<Vec<_>>())
+        } else {
+            opt.exts.clone().unwrap_or_default()
+        },
+        profile: opt.profile,
+        with_debuginfo: opt.with_debuginfo,
+        split_debuginfo: opt.split_debuginfo,
+        stack_size: opt.stack_size,
+        asyncify_stack_size: opt.asyncify_stack_size,
+        memory: MemoryConfig {
+            initial: opt.initial_memory,
+            maximum: opt.max_memory,
+            import: opt.import_memory,
+            export: opt.export_memory,
+            global_base: opt.global_base,
+        },
+        exec_model: opt.exec_model,
+        post_link: config.post_link.clone(),
+    };
+    let variants = if config.variants.is_empty() {
+        if opt.output.is_none() {
+            bail!("-o is required unless variants are given in the config file");
+        }
+        vec![base_variant]
+    } else {
+        if opt.output.is_some() {
+            bail!("-o cannot be used with variants given in the config file");
+        }
+        config.resolve_variants(&base_variant)?
+    };
+    let pipelines = variants
+        .iter()
+        .map(|variant| {
+            postlink::resolve_pipeline(&variant.post_link).with_context(|| {
+                format!("invalid post-link pipeline of variant '{}'", variant.name)
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for variant in &variants {
+        variant
+            .memory
+            .validate(variant.stack_size)
+            .with_context(|| format!("invalid memory settings of variant '{}'", variant.name))?;
+    }
+    let phase_name = |variant: &Variant, phase: &str| {
+        if variants.len() > 1 {
+            format!("{}: {}", variant.name, phase)
+        } else {
+            phase.to_string()
+        }
+    };
 
-    let installed_ruby_root = cruby.install_dir.join(cruby.prefix.strip_prefix("/")?);
-
-    if let Some(build_hook) = opt.build_hook {
-        run_build_hook(&build_hook, &installed_ruby_root)?;
+    // CRuby builds run one by one since make already uses every job, and
+    // variants with the same CRuby build input reuse the first build through
+    // its cache. Linking and post-link steps run in parallel below.
+    let mut crubies = vec![];
+    let mut linked_extensions = vec![];
+    for variant in &variants {
+        let enabled_extentions = variant
+            .exts
+            .resolve(available_extensions.as_deref().unwrap_or_default())?;
+        let mut extra_cc_args = opt.extra_cc_args.clone();
+        if variant.split_debuginfo {
+            extra_cc_args.push(String::from("-g"));
+        }
+        let mut input = CRubyBuildInput {
+            source: opt.cruby_src.clone(),
+            prefix: opt.guest_ruby_prefix.clone(),
+            asyncify_stack_size: variant.asyncify_stack_size,
+            extra_cc_args: &extra_cc_args,
+            extra_configure_args: &opt.extra_configure_args,
+            configure_env: &opt.configure_env,
+            transient_heap_total_size: opt.transient_heap_total_size,
+            make_vars: &opt.make_vars,
+            command_overrides: &command_overrides,
+            profile: variant.profile,
+            gem_exts: gems.iter().flat_map(|gem| gem.exts.clone()).collect(),
+            enabled_extentions: enabled_extentions.iter().map(String::as_str).collect(),
+            baseruby: baseruby.clone(),
+            reproducible: reproducible.clone(),
+        };
+        // without the source, the selection can't be narrowed to what ext/
+        // has. A build needs the source anyway, so resolve it again then.
+        let narrowed_extensions;
+        if available_extensions.is_none() && !is_cruby_cached(&workspace, &toolchain, &input) {
+            let src_dir = install_build_src(&workspace, &opt.cruby_src)?;
+            let available = ext::available_extensions(&src_dir)?;
+            narrowed_extensions = variant.exts.resolve(&available)?;
+            input.enabled_extentions = narrowed_extensions.iter().map(String::as_str).collect();
+            available_extensions = Some(available);
+        }
+        let cruby = build_cruby(&workspace, &toolchain, &input)
+            .with_context(|| format!("failed to build CRuby for variant '{}'", variant.name))?;
+        crubies.push(cruby);
+        let mut extensions = input
+            .enabled_extentions
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        extensions.extend(
+            gems.iter()
+                .flat_map(|gem| gem.exts.iter().map(|ext| ext.name.clone())),
+        );
+        linked_extensions.push(extensions);
     }
 
-    let mut map_paths = if !opt.no_builtin_files {
-        builtin_map_paths(&installed_ruby_root)?
+    // objects shared by variants built from the same CRuby
+    let mut objects_by_cruby: HashMap<PathBuf, Vec<(String, Vec<u8>)>> = HashMap::new();
+    let mut vfs_map_paths_by_cruby = HashMap::new();
+    let preset_args = if !opt.preset_args.is_empty() {
+        let bytes = workspace.phase("mkargs", || {
+            mkargs(
+                &workspace,
+                &toolchain,
+                &opt.preset_args,
+                &reproducible_cflags,
+            )
+        })?;
+        Some(bytes)
     } else {
-        vec![]
+        None
     };
-    map_paths.extend(opt.map_dirs);
+    for (variant, cruby) in variants.iter().zip(&crubies) {
+        if objects_by_cruby.contains_key(&cruby.install_dir) {
+            continue;
+        }
+        let installed_ruby_root = cruby.installed_ruby_root();
 
-    let mut raw_objects = vec![];
+        if let Some(build_hook) = &opt.build_hook {
+            run_build_hook(build_hook, &installed_ruby_root)?;
+        }
 
-    if !map_paths.is_empty() {
-        let input = MkfsInput {
-            map_paths,
-            host_ruby_root: &installed_ruby_root,
-            guest_ruby_root: &cruby.prefix.strip_prefix("/embd-root").unwrap(),
+        let mut map_paths = if !opt.no_builtin_files {
+            builtin_map_paths(&installed_ruby_root)?
+        } else {
+            vec![]
         };
-        let bytes = mkfs(&workspace, &toolchain, input)?;
-        raw_objects.push(("fs.o".to_string(), bytes));
+        for gem in &gems {
+            map_paths.extend(gem.lib_map_paths()?);
+        }
+        map_paths.extend(opt.map_dirs.iter().cloned());
+
+        let mut raw_objects = vec![];
+        if !map_paths.is_empty() {
+            let input = MkfsInput {
+                map_paths,
+                host_ruby_root: &installed_ruby_root,
+                guest_ruby_root: &cruby.prefix,
+            };
+            vfs_map_paths_by_cruby.insert(cruby.install_dir.clone(), input.expanded_map_paths());
+            let bytes = workspace.phase(&phase_name(variant, "mkfs"), || {
+                mkfs(&workspace, &toolchain, input, &reproducible_cflags)
+            })?;
+            raw_objects.push(("fs.o".to_string(), bytes));
+        }
+        if let Some(bytes) = &preset_args {
+            raw_objects.push(("preset_args.o".to_string(), bytes.clone()));
+        }
+        objects_by_cruby.insert(cruby.install_dir.clone(), raw_objects);
     }
 
-    if !opt.preset_args.is_empty() {
-        let bytes = mkargs(&workspace, &toolchain, &opt.preset_args)?;
-        raw_objects.push(("preset_args.o".to_string(), bytes));
+    let mut artifacts = vec![];
+    let mut linker_inputs = vec![];
+    let mut allowed_imports = vec![];
+    for (variant, cruby) in variants.iter().zip(&crubies) {
+        let mut raw_objects = objects_by_cruby[&cruby.install_dir].clone();
+        if variant.exec_model == ExecModel::Reactor {
+            let bytes = workspace.phase(&phase_name(variant, "reactor shim"), || {
+                build_reactor_shim(&workspace, &toolchain, cruby, &reproducible_cflags)
+            })?;
+            raw_objects.push(("reactor.o".to_string(), bytes));
+        }
+        for (name, bytes) in &raw_objects {
+            let name = if variants.len() > 1 {
+                format!("{}: {}", variant.name, name)
+            } else {
+                name.clone()
+            };
+            artifacts.push((name, bytes.len() as u64));
+        }
+        let mut linker_input = LinkerInput {
+            stack_size: variant.stack_size,
+            memory: variant.memory.clone(),
+            exec_model: variant.exec_model,
+            exports: &exports,
+            raw_objects,
+            object_files: vec![],
+            extra_args: &opt.extra_linker_args,
+        };
+        let builtin_objects = linker_input.raw_objects.len();
+        if !opt.link_files.is_empty() {
+            let mut cflags = variant
+                .profile
+                .cflags()
+                .iter()
+                .map(|flag| flag.to_string())
+                .collect::<Vec<_>>();
+            cflags.extend(opt.extra_cc_args.iter().cloned());
+            cflags.extend(reproducible_cflags.iter().cloned());
+            workspace.phase(&phase_name(variant, "link inputs"), || {
+                for path in &opt.link_files {
+                    linker_input.add_link_file(&workspace, &toolchain, path, &cflags)?;
+                }
+                Ok(())
+            })?;
+        }
+        let mut allowed = opt.allowed_imports.clone();
+        allowed.extend(asyncify.imports.iter().cloned());
+        if variant.memory.import {
+            allowed.push(String::from("env.memory"));
+        }
+        // functions --link files leave undefined are provided by the host
+        for (name, object) in &linker_input.raw_objects[builtin_objects..] {
+            allowed.extend(
+                wasm::object_imports(object)
+                    .with_context(|| format!("failed to parse {}", name))?,
+            );
+        }
+        for path in &linker_input.object_files {
+            let object =
+                std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+            allowed.extend(
+                wasm::object_imports(&object)
+                    .with_context(|| format!("failed to parse {:?}", path))?,
+            );
+        }
+        allowed_imports.push(allowed);
+        linker_inputs.push(linker_input);
     }
 
-    let linker_input = LinkerInput {
-        stack_size: opt.stack_size,
-        raw_objects,
-        extra_args: &opt.extra_linker_args,
-    };
+    // link and asyncify independent variants in parallel
+    std::thread::scope(|scope| {
+        let handles = variants
+            .iter()
+            .zip(&crubies)
+            .zip(&linker_inputs)
+            .zip(&pipelines)
+            .zip(&allowed_imports)
+            .map(|((((variant, cruby), linker_input), pipeline), allowed)| {
+                let workspace = &workspace;
+                let extra_wasm_opt_args = &opt.extra_wasm_opt_args;
+                let toolchain = &toolchain;
+                let phase_name = &phase_name;
+                let asyncify = &asyncify;
+                let asyncify_pass_args = &asyncify_pass_args;
+                scope.spawn(move || {
+                    workspace.phase(&phase_name(variant, "link"), || {
+                        link_executable(workspace, toolchain, cruby, linker_input, &variant.output)
+                    })?;
+                    wasm::validate(&variant.output).context("module is invalid after link")?;
+                    let options = PostLinkOptions {
+                        profile: variant.profile,
+                        with_debuginfo: variant.with_debuginfo
+                            || variant.split_debuginfo
+                            || variant.profile.keeps_debuginfo(),
+                        asyncify_pass_args,
+                        extra_wasm_opt_args,
+                    };
+                    for (i, step) in pipeline.iter().enumerate() {
+                        let phase = match step {
+                            PostLinkStep::Asyncify => {
+                                asyncify.verify(&variant.output)?;
+                                step.to_string()
+                            }
+                            _ => format!("{} #{}", step, i + 1),
+                        };
+                        workspace.phase(&phase_name(variant, &phase), || {
+                            postlink::run_post_link_step(toolchain, step, &options, &variant.output)
+                        })?;
+                        wasm::validate(&variant.output)
+                            .with_context(|| format!("module is invalid after {}", phase))?;
+                    }
+                    wasm::verify_imports(&variant.output, allowed)?;
+                    let required_exports = variant
+                        .exec_model
+                        .exports()
+                        .iter()
+                        .map(|name| name.to_string())
+                        .chain(linker_input.exports.iter().cloned())
+                        .collect::<Vec<_>>();
+                    wasm::verify_exports(&variant.output, &required_exports)?;
+                    variant.memory.verify(&variant.output, variant.stack_size)?;
+                    if variant.split_debuginfo {
+                        workspace.phase(&phase_name(variant, "split debuginfo"), || {
+                            debuginfo::split_debuginfo(
+                                &variant.output,
+                                &debuginfo::debug_file_path(&variant.output),
+                            )
+                        })?;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for (handle, variant) in handles.into_iter().zip(&variants) {
+            handle
+                .join()
+                .unwrap()
+                .with_context(|| format!("failed to build variant '{}'", variant.name))?;
+        }
+        anyhow::Ok(())
+    })?;
 
-    link_executable(
-        &mut workspace,
-        &toolchain,
-        &cruby,
-        &linker_input,
-        &opt.output,
-    )?;
-    asyncify_executable(&toolchain, opt.with_debuginfo, &opt.output, &opt.output)?;
+    if opt.size_report || opt.size_report_json {
+        for ((variant, cruby), (linker_input, extensions)) in variants
+            .iter()
+            .zip(&crubies)
+            .zip(linker_inputs.iter().zip(&linked_extensions))
+        {
+            let mut report = SizeReport::from_module(&variant.output, SIZE_REPORT_TOP)?;
+            for (name, bytes) in &linker_input.raw_objects {
+                match name.as_str() {
+                    "fs.o" => {
+                        report.vfs = Some(size_report::vfs_size(
+                            bytes,
+                            &vfs_map_paths_by_cruby[&cruby.install_dir],
+                            SIZE_REPORT_TOP,
+                        )?)
+                    }
+                    "preset_args.o" => {
+                        report.preset_args = Some(size_report::object_payload_size(bytes)?)
+                    }
+                    _ => {}
+                }
+            }
+            report.extensions = size_report::extension_sizes(
+                &cruby.build_dir,
+                &extensions.iter().map(String::as_str).collect::<Vec<_>>(),
+            )?;
+            if opt.size_report {
+                report.print();
+            }
+            if opt.size_report_json {
+                let mut json_path = variant.output.clone().into_os_string();
+                json_path.push(".size.json");
+                std::fs::write(&json_path, report.to_json()?)
+                    .with_context(|| format!("failed to write {:?}", json_path))?;
+            }
+        }
+    }
+
+    let compiler_cache_stats = crubies
+        .iter()
+        .filter_map(|cruby| cruby.compiler_cache_stats)
+        .reduce(|total, stats| CompilerCacheStats {
+            hits: total.hits + stats.hits,
+            misses: total.misses + stats.misses,
+        });
+    BuildSummary {
+        phases: workspace.phases(),
+        outputs: variants
+            .iter()
+            .flat_map(|variant| {
+                let debug_file = variant
+                    .split_debuginfo
+                    .then(|| debuginfo::debug_file_path(&variant.output));
+                std::iter::once(variant.output.clone()).chain(debug_file)
+            })
+            .collect(),
+        artifacts,
+        compiler_cache: toolchain.compiler_cache.zip(compiler_cache_stats),
+    }
+    .print();
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parse_build_src;
+    use std::ffi::OsString;
+
+    use crate::{parse_build_src, parse_jobs, parse_key_value, parse_name_list};
+
+    #[test]
+    fn parse_configure_env() {
+        let (key, value) = parse_key_value("optflags=-O3 -g").expect("parse failed");
+        assert_eq!(key, "optflags");
+        assert_eq!(value, "-O3 -g");
+        assert!(parse_key_value("=value").is_err());
+        assert!(parse_key_value("novalue").is_err());
+    }
+
+    #[test]
+    fn parse_make_jobs() {
+        assert_eq!(parse_jobs("4").unwrap(), 4);
+        assert!(parse_jobs("0").is_err());
+        assert!(parse_jobs("-1").is_err());
+    }
+
+    #[test]
+    fn strip_verify_reproducible_args() {
+        let args = [
+            "-o",
+            "ruby.wasm",
+            "--reproducible",
+            "-g",
+            "-oout.wasm",
+            "--",
+            "-o",
+            "x",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+        assert_eq!(
+            super::strip_verify_reproducible_args(&args),
+            vec!["-g", "--", "-o", "x"]
+        );
+    }
+
+    #[test]
+    fn parse_export_file() {
+        let exports = parse_name_list("# embedding API\nrb_eval_string\n\n  rb_funcallv  \n");
+        assert_eq!(exports, vec!["rb_eval_string", "rb_funcallv"]);
+    }
 
     #[test]
     fn parse_build_source_github() {
@@ -0,0 +1,267 @@
+//! Linear memory settings of the output module
+//!
+//! `wasm-ld` lays out memory as stack, then data, then heap (`--stack-first`).
+//! With a global base, data starts there and the stack follows it instead,
+//! since wasm-ld ignores `--global-base` under `--stack-first`. The settings
+//! are checked against each other before linking, and against the final
+//! module since `wasm-opt` rewrites it.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::wasm;
+
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Largest 32-bit memory in bytes
+const MAX_MEMORY_SIZE: u64 = 65536 * WASM_PAGE_SIZE;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryConfig {
+    /// Initial memory in bytes, a multiple of the page size
+    pub initial: Option<u64>,
+    /// Maximum memory in bytes, a multiple of the page size
+    pub maximum: Option<u64>,
+    /// Import memory from the host instead of defining it
+    pub import: bool,
+    /// Export memory even when it's imported
+    pub export: bool,
+    /// Address where data starts, followed by the stack
+    pub global_base: Option<u64>,
+}
+
+impl MemoryConfig {
+    /// Lowest address of data, right after the stack unless a global base
+    /// is given
+    fn data_start(&self, stack_size: u64) -> u64 {
+        self.global_base.unwrap_or(stack_size)
+    }
+
+    /// Memory needed by stack and data ending at `data_end`
+    fn used_memory(&self, data_end: u64, stack_size: u64) -> u64 {
+        match self.global_base {
+            Some(_) => data_end + stack_size,
+            None => data_end,
+        }
+    }
+
+    /// Check the settings against each other and the stack size before
+    /// linking. Data size is only known after linking; see `verify`.
+    pub fn validate(&self, stack_size: usize) -> anyhow::Result<()> {
+        let stack_size = stack_size as u64;
+        for (name, size) in [("initial", self.initial), ("maximum", self.maximum)] {
+            let size = match size {
+                Some(size) => size,
+                None => continue,
+            };
+            if size % WASM_PAGE_SIZE != 0 {
+                bail!(
+                    "{} memory {} is not a multiple of the page size {}",
+                    name,
+                    size,
+                    WASM_PAGE_SIZE
+                );
+            }
+            if size > MAX_MEMORY_SIZE {
+                bail!(
+                    "{} memory {} exceeds the 4GiB limit of 32-bit memory",
+                    name,
+                    size
+                );
+            }
+        }
+        if let (Some(initial), Some(maximum)) = (self.initial, self.maximum) {
+            if initial > maximum {
+                bail!(
+                    "initial memory {} is larger than maximum memory {}",
+                    initial,
+                    maximum
+                );
+            }
+        }
+        let data_start = self.data_start(stack_size);
+        for (name, size) in [("initial", self.initial), ("maximum", self.maximum)] {
+            if let Some(size) = size {
+                if size <= self.used_memory(data_start, stack_size) {
+                    bail!(
+                        "{} memory {} leaves no room for data starting at {} and the stack of {} bytes",
+                        name,
+                        size,
+                        data_start,
+                        stack_size
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn linker_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if self.global_base.is_none() {
+            args.push("--stack-first".to_string());
+        }
+        if let Some(initial) = self.initial {
+            args.push(format!("--initial-memory={}", initial));
+        }
+        if let Some(maximum) = self.maximum {
+            args.push(format!("--max-memory={}", maximum));
+        }
+        if self.import {
+            args.push("--import-memory".to_string());
+        }
+        if self.export {
+            args.push("--export-memory".to_string());
+        }
+        if let Some(global_base) = self.global_base {
+            args.push(format!("--global-base={}", global_base));
+        }
+        args
+    }
+
+    /// Fail if the module at `path` doesn't have the requested memory, or its
+    /// stack and data don't fit in the initial memory
+    pub fn verify(&self, path: &Path, stack_size: usize) -> anyhow::Result<()> {
+        let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        let layout =
+            wasm::memory_layout(&module).with_context(|| format!("failed to parse {:?}", path))?;
+        self.verify_layout(&layout, stack_size as u64)
+            .with_context(|| format!("unexpected memory in {:?}", path))
+    }
+
+    fn verify_layout(&self, layout: &wasm::MemoryLayout, stack_size: u64) -> anyhow::Result<()> {
+        if layout.imported != self.import {
+            bail!(
+                "memory is {}",
+                if layout.imported {
+                    "imported"
+                } else {
+                    "not imported"
+                }
+            );
+        }
+        if (self.export || !self.import) && !layout.exported {
+            bail!("memory is not exported");
+        }
+        let initial = layout.initial_pages * WASM_PAGE_SIZE;
+        if let Some(expected) = self.initial {
+            if initial != expected {
+                bail!("initial memory is {}, expected {}", initial, expected);
+            }
+        }
+        let maximum = layout.maximum_pages.map(|pages| pages * WASM_PAGE_SIZE);
+        if let Some(expected) = self.maximum {
+            if maximum != Some(expected) {
+                bail!("maximum memory is {:?}, expected {}", maximum, expected);
+            }
+        }
+        if let Some((data_start, data_end)) = layout.data_range {
+            if data_start < self.data_start(stack_size) {
+                bail!(
+                    "data starts at {}, below the expected start {}",
+                    data_start,
+                    self.data_start(stack_size)
+                );
+            }
+            let used = self.used_memory(data_end, stack_size);
+            if used > initial {
+                bail!(
+                    "initial memory {} is smaller than stack and data ending at {}",
+                    initial,
+                    used
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryConfig, WASM_PAGE_SIZE};
+    use crate::wasm::MemoryLayout;
+
+    #[test]
+    fn test_validate_memory_config() {
+        let stack_size = 16 * WASM_PAGE_SIZE as usize;
+        let config = MemoryConfig {
+            initial: Some(32 * WASM_PAGE_SIZE),
+            maximum: Some(64 * WASM_PAGE_SIZE),
+            global_base: Some(4 * WASM_PAGE_SIZE),
+            ..Default::default()
+        };
+        config.validate(stack_size).unwrap();
+        assert_eq!(
+            config.linker_args(),
+            vec![
+                "--initial-memory=2097152",
+                "--max-memory=4194304",
+                "--global-base=262144"
+            ]
+        );
+        assert_eq!(MemoryConfig::default().linker_args(), vec!["--stack-first"]);
+
+        let invalid = [
+            MemoryConfig {
+                initial: Some(WASM_PAGE_SIZE + 1),
+                ..Default::default()
+            },
+            MemoryConfig {
+                initial: Some(64 * WASM_PAGE_SIZE),
+                maximum: Some(32 * WASM_PAGE_SIZE),
+                ..Default::default()
+            },
+            MemoryConfig {
+                initial: Some(32 * WASM_PAGE_SIZE),
+                global_base: Some(20 * WASM_PAGE_SIZE),
+                ..Default::default()
+            },
+            MemoryConfig {
+                initial: Some(16 * WASM_PAGE_SIZE),
+                ..Default::default()
+            },
+            MemoryConfig {
+                maximum: Some(1 << 33),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate(stack_size).is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_verify_memory_layout() {
+        let config = MemoryConfig {
+            initial: Some(2 * WASM_PAGE_SIZE),
+            import: true,
+            ..Default::default()
+        };
+        let mut layout = MemoryLayout {
+            imported: true,
+            exported: false,
+            initial_pages: 2,
+            maximum_pages: None,
+            data_range: Some((1024, 2048)),
+        };
+        config.verify_layout(&layout, 1024).unwrap();
+        // data doesn't fit in the initial memory
+        layout.data_range = Some((1024, 3 * WASM_PAGE_SIZE));
+        assert!(config.verify_layout(&layout, 1024).is_err());
+        // data overlaps the stack
+        layout.data_range = Some((512, 2048));
+        assert!(config.verify_layout(&layout, 1024).is_err());
+        // the stack follows data placed at the global base
+        let config = MemoryConfig {
+            global_base: Some(1024),
+            ..config
+        };
+        layout.data_range = Some((1024, WASM_PAGE_SIZE));
+        config.verify_layout(&layout, 1024).unwrap();
+        assert!(config.verify_layout(&layout, 2 * WASM_PAGE_SIZE).is_err());
+        layout.data_range = None;
+        layout.imported = false;
+        assert!(config.verify_layout(&layout, 1024).is_err());
+    }
+}
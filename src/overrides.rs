@@ -0,0 +1,145 @@
+//! Overrides of commands looked up in PATH while building CRuby
+//!
+//! Host tools like `ruby`, `strip` or `wasm-opt` can leak into the cross build
+//! through PATH. Each override places a small script with the command's name
+//! in a directory prepended to PATH during `make install`.
+
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OverrideAction {
+    /// Replace the command with a no-op which always succeeds
+    Shadow,
+    /// Replace the command with another program
+    Replace(PathBuf),
+    /// Run the original command through a wrapper, as `WRAPPER COMMAND ARGS...`
+    Wrap(PathBuf),
+}
+
+/// Command override given as `NAME=shadow`, `NAME=replace:PATH` or
+/// `NAME=wrap:PATH`, from `--override-command` or `command-overrides` in the
+/// config file
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CommandOverride {
+    pub name: String,
+    pub action: OverrideAction,
+}
+
+impl CommandOverride {
+    pub fn shadow(name: &str) -> CommandOverride {
+        CommandOverride {
+            name: name.to_string(),
+            action: OverrideAction::Shadow,
+        }
+    }
+
+    /// Shell script placed in PATH under the command's name
+    pub(crate) fn script(&self) -> anyhow::Result<String> {
+        let script = match &self.action {
+            OverrideAction::Shadow => {
+                let true_bin = which::which("true").context("true command not found")?;
+                format!("#!{}\n", true_bin.to_string_lossy())
+            }
+            OverrideAction::Replace(program) => {
+                let program = which::which(program).with_context(|| {
+                    format!("replacement of {} not found: {:?}", self.name, program)
+                })?;
+                format!("#!/bin/sh\nexec {} \"$@\"\n", shell_quote(&program))
+            }
+            OverrideAction::Wrap(wrapper) => {
+                let wrapper = which::which(wrapper).with_context(|| {
+                    format!("wrapper of {} not found: {:?}", self.name, wrapper)
+                })?;
+                let original = which::which(&self.name)
+                    .with_context(|| format!("{} to be wrapped not found in PATH", self.name))?;
+                format!(
+                    "#!/bin/sh\nexec {} {} \"$@\"\n",
+                    shell_quote(&wrapper),
+                    shell_quote(&original)
+                )
+            }
+        };
+        Ok(script)
+    }
+}
+
+fn shell_quote(path: &std::path::Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+impl FromStr for CommandOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, action) = match s.split_once('=') {
+            Some((name, action)) if !name.is_empty() => (name, action),
+            _ => bail!("command override must be in NAME=ACTION form: {}", s),
+        };
+        if name.contains('/') {
+            bail!("command name must not contain '/': {}", name);
+        }
+        let action = if action == "shadow" {
+            OverrideAction::Shadow
+        } else if let Some(program) = action.strip_prefix("replace:") {
+            OverrideAction::Replace(program.into())
+        } else if let Some(wrapper) = action.strip_prefix("wrap:") {
+            OverrideAction::Wrap(wrapper.into())
+        } else {
+            bail!(
+                "unknown override action '{}'; expected shadow, replace:PATH or wrap:PATH",
+                action
+            );
+        };
+        Ok(CommandOverride {
+            name: name.to_string(),
+            action,
+        })
+    }
+}
+
+impl TryFrom<String> for CommandOverride {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for CommandOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.action {
+            OverrideAction::Shadow => write!(f, "{}=shadow", self.name),
+            OverrideAction::Replace(program) => {
+                write!(f, "{}=replace:{}", self.name, program.display())
+            }
+            OverrideAction::Wrap(wrapper) => write!(f, "{}=wrap:{}", self.name, wrapper.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{CommandOverride, OverrideAction};
+
+    #[test]
+    fn test_parse_command_override() {
+        let shadow: CommandOverride = "ruby=shadow".parse().unwrap();
+        assert_eq!(shadow, CommandOverride::shadow("ruby"));
+        let replace: CommandOverride = "strip=replace:/opt/llvm/bin/llvm-strip".parse().unwrap();
+        assert_eq!(
+            replace.action,
+            OverrideAction::Replace(PathBuf::from("/opt/llvm/bin/llvm-strip"))
+        );
+        let wrap: CommandOverride = "strip=wrap:time".parse().unwrap();
+        assert_eq!(wrap.action, OverrideAction::Wrap(PathBuf::from("time")));
+        assert!("ruby".parse::<CommandOverride>().is_err());
+        assert!("ruby=hide".parse::<CommandOverride>().is_err());
+        assert!("bin/ruby=shadow".parse::<CommandOverride>().is_err());
+    }
+}
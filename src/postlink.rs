@@ -0,0 +1,200 @@
+//! Binaryen passes run in order on the linked module
+//!
+//! ```toml
+//! [[post-link]]
+//! tool = "asyncify"
+//!
+//! [[post-link]]
+//! tool = "wasm-opt"
+//! args = ["-Oz", "--converge", "--strip-producers"]
+//!
+//! [[post-link]]
+//! tool = "wasm-metadce"
+//! graph = "metadce-roots.json"
+//! ```
+//!
+//! Asyncify is required for CRuby to work, so it runs first unless the
+//! pipeline places it somewhere.
+
+use std::{fmt, path::Path, path::PathBuf, process::Command};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::{
+    asyncify_executable, summary::format_size, toolchain::Toolchain, ui::trace_command_exec,
+    ui_info, BuildProfile,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "tool", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum PostLinkStep {
+    /// Asyncify with the arguments given on the command line
+    Asyncify,
+    /// wasm-opt with the given arguments
+    WasmOpt { args: Vec<String> },
+    /// wasm-metadce removing what isn't reachable from the roots in `graph`
+    WasmMetadce {
+        graph: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+impl fmt::Display for PostLinkStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostLinkStep::Asyncify => write!(f, "asyncify"),
+            PostLinkStep::WasmOpt { .. } => write!(f, "wasm-opt"),
+            PostLinkStep::WasmMetadce { .. } => write!(f, "wasm-metadce"),
+        }
+    }
+}
+
+/// Steps to run, with asyncify placed first if the pipeline doesn't have it
+pub fn resolve_pipeline(steps: &[PostLinkStep]) -> anyhow::Result<Vec<PostLinkStep>> {
+    let asyncify_steps = steps
+        .iter()
+        .filter(|step| **step == PostLinkStep::Asyncify)
+        .count();
+    if asyncify_steps > 1 {
+        bail!("post-link pipeline has {} asyncify steps", asyncify_steps);
+    }
+    let mut pipeline = vec![];
+    if asyncify_steps == 0 {
+        pipeline.push(PostLinkStep::Asyncify);
+    }
+    pipeline.extend(steps.iter().cloned());
+    Ok(pipeline)
+}
+
+pub struct PostLinkOptions<'a> {
+    pub profile: BuildProfile,
+    /// Keep names and DWARF through every step
+    pub with_debuginfo: bool,
+    /// From `AsyncifyConfig::pass_args`
+    pub asyncify_pass_args: &'a [String],
+    /// Appended to wasm-opt arguments of the asyncify step
+    pub extra_wasm_opt_args: &'a [String],
+}
+
+/// Run a step rewriting the module at `path` in place
+pub fn run_post_link_step(
+    toolchain: &Toolchain,
+    step: &PostLinkStep,
+    options: &PostLinkOptions,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let size_before = module_size(path)?;
+    match step {
+        PostLinkStep::Asyncify => {
+            let args = [options.asyncify_pass_args, options.extra_wasm_opt_args].concat();
+            asyncify_executable(
+                toolchain,
+                options.profile,
+                options.with_debuginfo,
+                &args,
+                path,
+                path,
+            )?;
+        }
+        PostLinkStep::WasmOpt { args } => {
+            let mut wasm_opt = Command::new(&toolchain.wasm_opt);
+            wasm_opt.arg(path).args(args);
+            run_binaryen_tool(wasm_opt, options.with_debuginfo, path, "wasm-opt")?;
+        }
+        PostLinkStep::WasmMetadce { graph, args } => {
+            if !graph.is_file() {
+                bail!("wasm-metadce graph file not found: {:?}", graph);
+            }
+            let mut metadce = Command::new(toolchain.binaryen_tool("wasm-metadce")?);
+            metadce.arg(path).arg("--graph-file").arg(graph).args(args);
+            run_binaryen_tool(metadce, options.with_debuginfo, path, "wasm-metadce")?;
+        }
+    }
+    ui_info!(
+        "{}: {} -> {}",
+        step,
+        format_size(size_before),
+        format_size(module_size(path)?)
+    );
+    Ok(())
+}
+
+fn run_binaryen_tool(
+    mut command: Command,
+    with_debuginfo: bool,
+    path: &Path,
+    description: &str,
+) -> anyhow::Result<()> {
+    // binaryen tools drop names and DWARF unless asked to keep them
+    if with_debuginfo {
+        command.arg("-g");
+    }
+    command.arg("-o").arg(path);
+    trace_command_exec(&command, description, None);
+    let status = command
+        .status()
+        .with_context(|| format!("failed to spawn {}", description))?;
+    if !status.success() {
+        bail!("{} failed", description)
+    }
+    Ok(())
+}
+
+fn module_size(path: &Path) -> anyhow::Result<u64> {
+    Ok(std::fs::metadata(path)
+        .with_context(|| format!("failed to stat {:?}", path))?
+        .len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde::Deserialize;
+
+    use super::{resolve_pipeline, PostLinkStep};
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Pipeline {
+        post_link: Vec<PostLinkStep>,
+    }
+
+    #[test]
+    fn test_resolve_pipeline() {
+        let pipeline: Pipeline = toml::from_str(
+            r#"
+[[post-link]]
+tool = "wasm-opt"
+args = ["-Oz"]
+
+[[post-link]]
+tool = "wasm-metadce"
+graph = "roots.json"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_pipeline(&pipeline.post_link).unwrap(),
+            vec![
+                PostLinkStep::Asyncify,
+                PostLinkStep::WasmOpt {
+                    args: vec![String::from("-Oz")]
+                },
+                PostLinkStep::WasmMetadce {
+                    graph: PathBuf::from("roots.json"),
+                    args: vec![]
+                },
+            ]
+        );
+        let steps = vec![
+            PostLinkStep::WasmOpt { args: vec![] },
+            PostLinkStep::Asyncify,
+        ];
+        assert_eq!(resolve_pipeline(&steps).unwrap(), steps);
+        assert!(resolve_pipeline(&[PostLinkStep::Asyncify, PostLinkStep::Asyncify]).is_err());
+        assert!(toml::from_str::<Pipeline>("[[post-link]]\ntool = \"wasm-strip\"").is_err());
+    }
+}
@@ -0,0 +1,177 @@
+// Embedding API exported by ruby.wasm built with `--exec-model reactor`.
+//
+// The host calls `_initialize` once, then `rbwasm_init`, and can call the other
+// exports any number of times on the same VM. VALUEs returned by evaluations
+// and method calls are retained until released with `rbwasm_release`.
+// Functions taking `state` store a non-zero value there when an exception was
+// raised, which can be fetched with `rbwasm_last_error`. `_initialize` comes
+// from wasi-libc's crt1-reactor.o linked along with this file.
+
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "ruby.h"
+
+#define RBWASM_EXPORT(name) __attribute__((export_name(#name)))
+
+// Provided by CRuby's wasm runtime. Every entry into the VM must go through it
+// so that setjmp/longjmp emulated with Asyncify can unwind and rewind.
+int rb_wasm_rt_start(int(main)(int argc, char **argv), int argc, char **argv);
+
+static VALUE retained_values = Qfalse;
+static VALUE last_error = Qnil;
+
+static VALUE retain(VALUE value) {
+  if (SPECIAL_CONST_P(value)) {
+    return value;
+  }
+  VALUE count = rb_hash_lookup2(retained_values, value, INT2FIX(0));
+  rb_hash_aset(retained_values, value, INT2FIX(FIX2INT(count) + 1));
+  return value;
+}
+
+struct call {
+  VALUE (*func)(VALUE);
+  VALUE arg;
+  // whether the result is a VALUE to be retained for the host
+  int retain;
+  VALUE result;
+  int state;
+};
+
+// The call being made by call_in_vm, since rb_wasm_rt_start only passes
+// main's arguments. Saved and restored around each call for the host calling
+// back into the VM while inside one.
+static struct call *current_call = NULL;
+
+static int call_in_vm(int argc, char **argv) {
+  struct call *call = current_call;
+  call->result = rb_protect(call->func, call->arg, &call->state);
+  if (call->state) {
+    last_error = rb_errinfo();
+    rb_set_errinfo(Qnil);
+    call->result = Qnil;
+  } else if (call->retain) {
+    retain(call->result);
+  }
+  return 0;
+}
+
+static void start_call(struct call *call) {
+  struct call *outer = current_call;
+  current_call = call;
+  rb_wasm_rt_start(call_in_vm, 0, NULL);
+  current_call = outer;
+}
+
+static VALUE call_protected(VALUE (*func)(VALUE), VALUE arg, int32_t *state) {
+  struct call call = {func, arg, 1, Qnil, 0};
+  start_call(&call);
+  if (state) {
+    *state = call.state;
+  }
+  return call.result;
+}
+
+static int init_vm(int argc, char **argv) {
+  char *args[] = {"ruby", "-EUTF-8", "-e_=0", NULL};
+  int args_count = 3;
+  char **args_ptr = args;
+  ruby_sysinit(&args_count, &args_ptr);
+  ruby_init();
+  // load paths, static extensions and gem prelude are set up with options
+  ruby_options(args_count, args_ptr);
+  retained_values = rb_hash_new();
+  rb_funcall(retained_values, rb_intern("compare_by_identity"), 0);
+  rb_gc_register_address(&retained_values);
+  rb_gc_register_address(&last_error);
+  return 0;
+}
+
+RBWASM_EXPORT(rbwasm_init)
+int32_t rbwasm_init(void) {
+  if (retained_values != Qfalse) {
+    return 1;
+  }
+  return rb_wasm_rt_start(init_vm, 0, NULL);
+}
+
+static VALUE eval_string(VALUE src) { return rb_eval_string((const char *)src); }
+
+RBWASM_EXPORT(rbwasm_eval_string)
+VALUE rbwasm_eval_string(const char *src, int32_t *state) {
+  return call_protected(eval_string, (VALUE)src, state);
+}
+
+static VALUE eval_file(VALUE path) {
+  rb_load(rb_str_new_cstr((const char *)path), 0);
+  return Qtrue;
+}
+
+RBWASM_EXPORT(rbwasm_eval_file)
+VALUE rbwasm_eval_file(const char *path, int32_t *state) {
+  return call_protected(eval_file, (VALUE)path, state);
+}
+
+struct funcall {
+  VALUE recv;
+  const char *method;
+  int argc;
+  const VALUE *argv;
+};
+
+static VALUE funcall(VALUE arg) {
+  struct funcall *call = (struct funcall *)arg;
+  return rb_funcallv(call->recv, rb_intern(call->method), call->argc,
+                     call->argv);
+}
+
+RBWASM_EXPORT(rbwasm_funcall)
+VALUE rbwasm_funcall(VALUE recv, const char *method, int32_t argc,
+                     const VALUE *argv, int32_t *state) {
+  struct funcall call = {recv, method, argc, argv};
+  return call_protected(funcall, (VALUE)&call, state);
+}
+
+static VALUE to_cstr(VALUE value) {
+  VALUE str = rb_obj_as_string(value);
+  char *cstr = malloc(RSTRING_LEN(str) + 1);
+  memcpy(cstr, RSTRING_PTR(str), RSTRING_LEN(str));
+  cstr[RSTRING_LEN(str)] = '\0';
+  return (VALUE)cstr;
+}
+
+// Returns `value.to_s` as a NUL-terminated string to be freed with rbwasm_free
+RBWASM_EXPORT(rbwasm_value_to_str)
+char *rbwasm_value_to_str(VALUE value, int32_t *state) {
+  struct call call = {to_cstr, value, 0, Qnil, 0};
+  start_call(&call);
+  if (state) {
+    *state = call.state;
+  }
+  return call.state ? NULL : (char *)call.result;
+}
+
+// The exception raised by the last failing call, valid until the next failure
+RBWASM_EXPORT(rbwasm_last_error)
+VALUE rbwasm_last_error(void) { return last_error; }
+
+RBWASM_EXPORT(rbwasm_release)
+void rbwasm_release(VALUE value) {
+  if (SPECIAL_CONST_P(value) || retained_values == Qfalse) {
+    return;
+  }
+  VALUE count = rb_hash_lookup2(retained_values, value, INT2FIX(0));
+  if (FIX2INT(count) <= 1) {
+    rb_hash_delete(retained_values, value);
+  } else {
+    rb_hash_aset(retained_values, value, INT2FIX(FIX2INT(count) - 1));
+  }
+}
+
+RBWASM_EXPORT(rbwasm_malloc)
+void *rbwasm_malloc(size_t size) { return malloc(size); }
+
+RBWASM_EXPORT(rbwasm_free)
+void rbwasm_free(void *ptr) { free(ptr); }
@@ -0,0 +1,188 @@
+//! Builds producing the same bytes from the same inputs on any machine
+//!
+//! Absolute paths of the workspace, the toolchain and the current directory
+//! are remapped to fixed ones in debug info and in installed files like
+//! `rbconfig.rb`, and `SOURCE_DATE_EPOCH` replaces the current time while
+//! building CRuby.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::{toolchain::Toolchain, wasm, Workspace};
+
+/// Used when `SOURCE_DATE_EPOCH` is not set: 1980-01-01, the earliest time
+/// zip archives can represent
+const DEFAULT_SOURCE_DATE_EPOCH: u64 = 315532800;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Reproducible {
+    /// Seconds since the Unix epoch used as the build time
+    pub source_date_epoch: u64,
+}
+
+impl Reproducible {
+    /// Settings from `SOURCE_DATE_EPOCH`, or a fixed time if it's not set
+    pub fn from_env() -> anyhow::Result<Reproducible> {
+        let source_date_epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid SOURCE_DATE_EPOCH: {:?}", value))?,
+            Err(_) => DEFAULT_SOURCE_DATE_EPOCH,
+        };
+        Ok(Reproducible { source_date_epoch })
+    }
+
+    pub fn envs(&self) -> [(&'static str, String); 1] {
+        [("SOURCE_DATE_EPOCH", self.source_date_epoch.to_string())]
+    }
+}
+
+/// Machine-specific directories and the fixed paths they are remapped to,
+/// longest first so that nested directories are remapped before their
+/// parents. `dirs` are remapped in addition, e.g. hashed build directories
+/// whose names depend on the toolchain location.
+pub fn path_remaps(
+    workspace: &Workspace,
+    toolchain: &Toolchain,
+    dirs: &[(&Path, &'static str)],
+) -> Vec<(PathBuf, &'static str)> {
+    let mut remaps = vec![
+        (workspace.dir.clone(), "/rbwasm"),
+        (toolchain.sysroot.clone(), "/wasi-sysroot"),
+    ];
+    remaps.extend(dirs.iter().map(|(dir, to)| (dir.to_path_buf(), *to)));
+    if let Some(bin_dir) = toolchain.cc.parent().filter(|dir| dir.is_absolute()) {
+        remaps.push((bin_dir.to_path_buf(), "/toolchain/bin"));
+    }
+    if let Ok(cwd) = std::env::current_dir() {
+        remaps.push((cwd, "."));
+    }
+    remaps.sort_by_key(|(from, _)| std::cmp::Reverse(from.as_os_str().len()));
+    remaps
+}
+
+/// Compiler flags remapping paths recorded in debug info and `__FILE__`.
+/// The last matching flag wins, so nested directories go last.
+pub fn prefix_map_flags(remaps: &[(PathBuf, &str)]) -> Vec<String> {
+    remaps
+        .iter()
+        .rev()
+        .map(|(from, to)| format!("-ffile-prefix-map={}={}", from.to_string_lossy(), to))
+        .collect()
+}
+
+fn remap_str(content: &str, remaps: &[(PathBuf, &str)]) -> String {
+    let mut content = content.to_string();
+    for (from, to) in remaps {
+        content = content.replace(from.to_string_lossy().as_ref(), to);
+    }
+    content
+}
+
+/// Remap paths recorded in installed text files describing the build, such
+/// as `rbconfig.rb` and pkg-config files
+pub fn remap_installed_files(install_dir: &Path, remaps: &[(PathBuf, &str)]) -> anyhow::Result<()> {
+    fn visit(dir: &Path, remaps: &[(PathBuf, &str)]) -> anyhow::Result<()> {
+        for entry in
+            std::fs::read_dir(dir).with_context(|| format!("failed to read dir: {:?}", dir))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                visit(&path, remaps)?;
+                continue;
+            }
+            let is_build_description = path.file_name().is_some_and(|name| name == "rbconfig.rb")
+                || path.extension().is_some_and(|ext| ext == "pc");
+            if !is_build_description {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {:?}", path))?;
+            let remapped = remap_str(&content, remaps);
+            if remapped != content {
+                log::debug!("remapped paths in {:?}", path);
+                std::fs::write(&path, remapped)
+                    .with_context(|| format!("failed to write {:?}", path))?;
+            }
+        }
+        Ok(())
+    }
+    visit(install_dir, remaps)
+}
+
+/// Fail with where two builds of the same module differ
+pub fn compare_outputs(a: &Path, b: &Path) -> anyhow::Result<()> {
+    let a_bytes = std::fs::read(a).with_context(|| format!("failed to read {:?}", a))?;
+    let b_bytes = std::fs::read(b).with_context(|| format!("failed to read {:?}", b))?;
+    let offset = match first_difference(&a_bytes, &b_bytes) {
+        Some(offset) => offset,
+        None => return Ok(()),
+    };
+    let section = wasm::raw_sections(&a_bytes).ok().and_then(|sections| {
+        sections
+            .into_iter()
+            .find(|section| {
+                let start = section.encoded.as_ptr() as usize - a_bytes.as_ptr() as usize;
+                (start..start + section.encoded.len()).contains(&offset)
+            })
+            .map(|section| match section.custom_name {
+                Some(name) => format!("custom section '{}'", name),
+                None => format!("section {}", section.id),
+            })
+    });
+    bail!(
+        "{:?} ({} bytes) and {:?} ({} bytes) differ at offset {:#x}{}",
+        a,
+        a_bytes.len(),
+        b,
+        b_bytes.len(),
+        offset,
+        section.map(|s| format!(" in {}", s)).unwrap_or_default()
+    )
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(a, b)| a != b) {
+        Some(offset) => Some(offset),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{first_difference, prefix_map_flags, remap_str};
+
+    #[test]
+    fn test_remap_paths() {
+        let remaps = vec![
+            (PathBuf::from("/home/user/app/.rbwasm"), "/rbwasm"),
+            (PathBuf::from("/home/user/app"), "."),
+        ];
+        assert_eq!(
+            remap_str(
+                "CONFIG[\"configure_args\"] = \"--with-destdir=/home/user/app/.rbwasm/cache/ruby-1\"\nsrcdir = /home/user/app/src",
+                &remaps
+            ),
+            "CONFIG[\"configure_args\"] = \"--with-destdir=/rbwasm/cache/ruby-1\"\nsrcdir = ./src"
+        );
+        assert_eq!(
+            prefix_map_flags(&remaps),
+            vec![
+                "-ffile-prefix-map=/home/user/app=.",
+                "-ffile-prefix-map=/home/user/app/.rbwasm=/rbwasm"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_first_difference() {
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"abc", b"ab"), Some(2));
+    }
+}
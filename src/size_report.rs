@@ -0,0 +1,246 @@
+//! Breakdown of where the bytes of a produced module come from
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use ansi_term::Style;
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{summary::format_size, wasm};
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct NamedSize {
+    pub name: String,
+    pub size: u64,
+}
+
+impl NamedSize {
+    fn new(name: impl Into<String>, size: u64) -> NamedSize {
+        NamedSize {
+            name: name.into(),
+            size,
+        }
+    }
+}
+
+/// Files embedded by mkfs, attributed by their size on the host
+#[derive(Debug, Serialize)]
+pub struct VfsSize {
+    /// Size of the data and code of the generated object
+    pub object: u64,
+    pub directories: Vec<NamedSize>,
+    pub files: Vec<NamedSize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SizeReport {
+    pub module: PathBuf,
+    pub total: u64,
+    pub sections: Vec<NamedSize>,
+    /// Whether the module has a name section to name functions
+    pub has_function_names: bool,
+    pub largest_functions: Vec<NamedSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vfs: Option<VfsSize>,
+    /// Size of the data and code of the preset args object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_args: Option<u64>,
+    /// Size of the data and code of each extension's objects before linking
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<NamedSize>,
+}
+
+impl SizeReport {
+    /// Analyze sections and functions of a module. Keeps the `top` largest
+    /// functions.
+    pub fn from_module(path: &Path, top: usize) -> anyhow::Result<SizeReport> {
+        let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        let sizes =
+            wasm::module_sizes(&module).with_context(|| format!("failed to parse {:?}", path))?;
+        let mut functions = sizes
+            .functions
+            .into_iter()
+            .map(|(name, size)| NamedSize::new(name, size))
+            .collect::<Vec<_>>();
+        sort_by_size(&mut functions);
+        functions.truncate(top);
+        Ok(SizeReport {
+            module: path.to_path_buf(),
+            total: sizes.total,
+            sections: sizes
+                .sections
+                .into_iter()
+                .map(|(name, size)| NamedSize::new(name, size))
+                .collect(),
+            has_function_names: sizes.has_names,
+            largest_functions: functions,
+            vfs: None,
+            preset_args: None,
+            extensions: vec![],
+        })
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn print(&self) {
+        fn print_sizes(title: &str, sizes: &[NamedSize], total: u64) {
+            eprintln!("  {}:", title);
+            let name_width = sizes.iter().map(|s| s.name.len()).max().unwrap_or(0);
+            for size in sizes {
+                eprintln!(
+                    "    {:width$}  {:>10}  {:>5.1}%",
+                    size.name,
+                    format_size(size.size),
+                    size.size as f64 * 100.0 / total.max(1) as f64,
+                    width = name_width
+                );
+            }
+        }
+        eprintln!(
+            "{} {:?} ({})",
+            Style::new().bold().paint("size report:"),
+            self.module,
+            format_size(self.total)
+        );
+        print_sizes("sections", &self.sections, self.total);
+        if !self.has_function_names {
+            eprintln!("  (no name section; build with -g to see function names)");
+        }
+        print_sizes("largest functions", &self.largest_functions, self.total);
+        if let Some(vfs) = &self.vfs {
+            eprintln!("  vfs object: {}", format_size(vfs.object));
+            print_sizes("vfs directories", &vfs.directories, self.total);
+            print_sizes("vfs files", &vfs.files, self.total);
+        }
+        if let Some(preset_args) = self.preset_args {
+            eprintln!("  preset args object: {}", format_size(preset_args));
+        }
+        if !self.extensions.is_empty() {
+            print_sizes("extensions (before linking)", &self.extensions, self.total);
+        }
+    }
+}
+
+fn sort_by_size(sizes: &mut [NamedSize]) {
+    sizes.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+}
+
+/// Size of the code and data in an object file
+pub fn object_payload_size(object: &[u8]) -> anyhow::Result<u64> {
+    let sizes = wasm::module_sizes(object)?;
+    Ok(sizes.section("code") + sizes.section("data"))
+}
+
+/// Attribute VFS bytes to guest files and their directories. Keeps the `top`
+/// largest entries of each.
+pub fn vfs_size(
+    object: &[u8],
+    map_paths: &[(PathBuf, PathBuf)],
+    top: usize,
+) -> anyhow::Result<VfsSize> {
+    fn visit(guest: &Path, host: &Path, files: &mut Vec<(PathBuf, u64)>) -> anyhow::Result<()> {
+        if host.is_dir() {
+            for entry in std::fs::read_dir(host)
+                .with_context(|| format!("failed to read dir: {:?}", host))?
+            {
+                let entry = entry?;
+                visit(&guest.join(entry.file_name()), &entry.path(), files)?;
+            }
+        } else {
+            let metadata =
+                std::fs::metadata(host).with_context(|| format!("failed to stat {:?}", host))?;
+            files.push((guest.to_path_buf(), metadata.len()));
+        }
+        Ok(())
+    }
+    let mut files = vec![];
+    for (guest, host) in map_paths {
+        visit(guest, host, &mut files)?;
+    }
+    let mut directories = BTreeMap::<PathBuf, u64>::new();
+    for (guest, size) in &files {
+        if let Some(parent) = guest.parent() {
+            *directories.entry(parent.to_path_buf()).or_default() += size;
+        }
+    }
+    let mut directories = directories
+        .into_iter()
+        .map(|(dir, size)| NamedSize::new(dir.to_string_lossy(), size))
+        .collect::<Vec<_>>();
+    let mut files = files
+        .into_iter()
+        .map(|(file, size)| NamedSize::new(file.to_string_lossy(), size))
+        .collect::<Vec<_>>();
+    sort_by_size(&mut directories);
+    sort_by_size(&mut files);
+    directories.truncate(top);
+    files.truncate(top);
+    Ok(VfsSize {
+        object: object_payload_size(object)?,
+        directories,
+        files,
+    })
+}
+
+/// Attribute object sizes in CRuby's build directory to extensions. Objects
+/// are looked up directly in `ext/<name>`, since nested directories are
+/// usually extensions on their own (e.g. `json/parser`).
+pub fn extension_sizes(build_dir: &Path, extensions: &[&str]) -> anyhow::Result<Vec<NamedSize>> {
+    let mut sizes = vec![];
+    for name in extensions {
+        let ext_dir = build_dir.join("ext").join(name);
+        if !ext_dir.is_dir() {
+            continue;
+        }
+        let mut size = 0;
+        for entry in std::fs::read_dir(&ext_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "o") {
+                let object = std::fs::read(&path)?;
+                size += object_payload_size(&object)
+                    .with_context(|| format!("failed to parse {:?}", path))?;
+            }
+        }
+        sizes.push(NamedSize::new(*name, size));
+    }
+    sort_by_size(&mut sizes);
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{vfs_size, NamedSize};
+
+    #[test]
+    fn test_vfs_size() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("lib/json")).unwrap();
+        std::fs::write(dir.path().join("lib/json.rb"), [0; 10]).unwrap();
+        std::fs::write(dir.path().join("lib/json/common.rb"), [0; 30]).unwrap();
+        std::fs::write(dir.path().join("lib/json/ext.rb"), [0; 5]).unwrap();
+        let map_paths = vec![(PathBuf::from("/usr/local/lib"), dir.path().join("lib"))];
+        let object = b"\0asm\x01\0\0\0";
+        let vfs = vfs_size(object, &map_paths, 2).unwrap();
+        assert_eq!(
+            vfs.directories,
+            vec![
+                NamedSize::new("/usr/local/lib/json", 35),
+                NamedSize::new("/usr/local/lib", 10)
+            ]
+        );
+        assert_eq!(
+            vfs.files,
+            vec![
+                NamedSize::new("/usr/local/lib/json/common.rb", 30),
+                NamedSize::new("/usr/local/lib/json.rb", 10)
+            ]
+        );
+    }
+}
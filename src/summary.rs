@@ -0,0 +1,119 @@
+//! Per-phase timing and the summary printed at the end of a build
+
+use std::{path::PathBuf, time::Duration};
+
+use ansi_term::Style;
+
+use crate::toolchain::{CompilerCache, CompilerCacheStats};
+
+#[derive(Debug, Clone)]
+pub struct PhaseRecord {
+    pub name: String,
+    pub duration: Duration,
+    /// The phase was skipped thanks to a cached result
+    pub cached: bool,
+}
+
+pub struct BuildSummary {
+    pub phases: Vec<PhaseRecord>,
+    pub outputs: Vec<PathBuf>,
+    /// Intermediate artifacts worth reporting with their sizes (e.g. VFS image)
+    pub artifacts: Vec<(String, u64)>,
+    pub compiler_cache: Option<(CompilerCache, CompilerCacheStats)>,
+}
+
+impl BuildSummary {
+    pub fn print(&self) {
+        eprintln!("{}", Style::new().bold().paint("build summary:"));
+        let name_width = self
+            .phases
+            .iter()
+            .map(|phase| phase.name.len())
+            .max()
+            .unwrap_or(0);
+        let mut total = Duration::ZERO;
+        for phase in &self.phases {
+            total += phase.duration;
+            eprintln!(
+                "  {:width$}  {:>8}{}",
+                phase.name,
+                format_duration(phase.duration),
+                if phase.cached { "  (cached)" } else { "" },
+                width = name_width
+            );
+        }
+        let cache_hits = self.phases.iter().filter(|phase| phase.cached).count();
+        eprintln!(
+            "  {:width$}  {:>8}  ({} cache hits)",
+            "total",
+            format_duration(total),
+            cache_hits,
+            width = name_width
+        );
+        for output in &self.outputs {
+            match std::fs::metadata(output) {
+                Ok(metadata) => {
+                    eprintln!("  output: {:?} ({})", output, format_size(metadata.len()))
+                }
+                Err(_) => eprintln!("  output: {:?}", output),
+            }
+        }
+        for (name, size) in &self.artifacts {
+            eprintln!("  {}: {}", name, format_size(*size));
+        }
+        if let Some((compiler_cache, stats)) = &self.compiler_cache {
+            let total = stats.hits + stats.misses;
+            let hit_rate = if total > 0 {
+                format!(
+                    " ({:.1}% hit rate)",
+                    stats.hits as f64 * 100.0 / total as f64
+                )
+            } else {
+                String::new()
+            };
+            eprintln!(
+                "  {}: {} hits, {} misses{}",
+                compiler_cache, stats.hits, stats.misses, hit_rate
+            );
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs >= 60.0 {
+        format!("{}m{:02}s", secs as u64 / 60, secs as u64 % 60)
+    } else {
+        format!("{:.1}s", secs)
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_duration, format_size};
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3 * 1024 * 1024 / 2), "1.5 MiB");
+        assert_eq!(format_duration(Duration::from_millis(1300)), "1.3s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m05s");
+    }
+}
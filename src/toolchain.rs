@@ -1,41 +1,643 @@
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Instant;
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use regex::Regex;
 
 use crate::{extract_tarball, relpath_for_display, ui_info, Workspace};
 
 pub struct Toolchain {
+    /// C compiler driver targeting wasm32-wasi
+    pub cc: PathBuf,
+    /// wasm-ld used for the final link
+    pub ld: PathBuf,
+    pub ar: PathBuf,
+    pub ranlib: PathBuf,
+    /// wasi-libc sysroot
+    pub sysroot: PathBuf,
     pub wasm_opt: PathBuf,
-    pub wasi_sdk: PathBuf,
+    pub make: Make,
+    /// Compiler cache wrapping `cc` while building CRuby. Not a part of
+    /// `identity` since it doesn't change produced objects.
+    pub compiler_cache: Option<CompilerCache>,
+    /// Describes where the toolchain came from (e.g. which prebuilt wasi-sdk
+    /// asset was chosen for the host). Used as a part of build cache keys.
+    pub identity: String,
 }
 
-pub fn install_build_toolchain(workspace: &Workspace) -> anyhow::Result<Toolchain> {
+impl Toolchain {
+    /// Assemble a toolchain from a wasi-sdk distribution tree
+    pub fn from_wasi_sdk(wasi_sdk: &Path, wasm_opt: PathBuf) -> Toolchain {
+        Toolchain {
+            identity: format!("wasi-sdk:{}", wasi_sdk.to_string_lossy()),
+            cc: wasi_sdk.join("bin/clang"),
+            ld: wasi_sdk.join("bin/wasm-ld"),
+            ar: wasi_sdk.join("bin/llvm-ar"),
+            ranlib: wasi_sdk.join("bin/llvm-ranlib"),
+            sysroot: wasi_sdk.join("share/wasi-sysroot"),
+            wasm_opt,
+            make: Make::default(),
+            compiler_cache: None,
+        }
+    }
+
+    fn components_for_identity(&self) -> String {
+        [&self.cc, &self.ld, &self.ar, &self.ranlib, &self.sysroot]
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Target flag for the compiler driver, which may be a host clang
+    /// defaulting to another target when taken from the system
+    pub fn target_flag(&self) -> &'static str {
+        "--target=wasm32-wasi"
+    }
+
+    /// `--sysroot` flag to compile and link against the wasi-libc sysroot
+    pub fn sysroot_flag(&self) -> String {
+        format!("--sysroot={}", self.sysroot.to_string_lossy())
+    }
+
+    /// Another binaryen tool (e.g. `wasm-metadce`), preferably from the same
+    /// installation as wasm-opt
+    pub fn binaryen_tool(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let sibling = self.wasm_opt.with_file_name(name);
+        if sibling.is_file() {
+            return Ok(sibling);
+        }
+        which::which(name).with_context(|| format!("{} command not found", name))
+    }
+}
+
+/// How to invoke make for every make-based build step
+#[derive(Debug, Clone)]
+pub struct Make {
+    pub program: PathBuf,
+    /// Number of parallel jobs. If `None`, defers to the jobserver of an outer
+    /// make given through MAKEFLAGS, or runs as many jobs as CPUs.
+    pub jobs: Option<usize>,
+}
+
+impl Default for Make {
+    fn default() -> Self {
+        Make {
+            program: PathBuf::from("make"),
+            jobs: None,
+        }
+    }
+}
+
+impl Make {
+    pub fn command(&self) -> Command {
+        let mut make = Command::new(&self.program);
+        if let Some(jobs) = self.jobs {
+            make.arg(format!("-j{}", jobs));
+        } else {
+            let makeflags = std::env::var("MAKEFLAGS").unwrap_or_default();
+            if !makeflags_controls_jobs(&makeflags) {
+                make.arg(format!("-j{}", num_cpus::get()));
+            }
+        }
+        make
+    }
+}
+
+/// Whether MAKEFLAGS inherited from an outer make already decides parallelism
+fn makeflags_controls_jobs(makeflags: &str) -> bool {
+    makeflags.split_whitespace().any(|flag| {
+        flag.starts_with("--jobserver-auth")
+            || flag.starts_with("--jobserver-fds")
+            || flag.starts_with("-j")
+    })
+}
+
+/// Compiler cache put in front of the C compiler given as `CC` to configure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerCache {
+    Ccache,
+    Sccache,
+}
+
+impl FromStr for CompilerCache {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ccache" => Ok(CompilerCache::Ccache),
+            "sccache" => Ok(CompilerCache::Sccache),
+            other => bail!(
+                "unknown compiler cache: {} (expected ccache or sccache)",
+                other
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CompilerCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.program_name())
+    }
+}
+
+impl CompilerCache {
+    fn program_name(&self) -> &'static str {
+        match self {
+            CompilerCache::Ccache => "ccache",
+            CompilerCache::Sccache => "sccache",
+        }
+    }
+
+    pub fn program(&self) -> anyhow::Result<PathBuf> {
+        which::which(self.program_name())
+            .with_context(|| format!("{} command not found", self.program_name()))
+    }
+
+    /// Environment variables for commands invoking the wrapped compiler.
+    /// Build directories differ per build input, so paths are hashed relative
+    /// to the workspace to share cache entries between them.
+    pub(crate) fn envs(&self, workspace_dir: &Path) -> Vec<(&'static str, PathBuf)> {
+        match self {
+            CompilerCache::Ccache => vec![("CCACHE_BASEDIR", workspace_dir.to_path_buf())],
+            CompilerCache::Sccache => vec![],
+        }
+    }
+
+    /// Query the cumulative hit and miss counts of the cache
+    pub(crate) fn stats(&self) -> anyhow::Result<CompilerCacheStats> {
+        let mut cmd = Command::new(self.program()?);
+        match self {
+            CompilerCache::Ccache => cmd.arg("--print-stats"),
+            CompilerCache::Sccache => cmd.args(["--show-stats", "--stats-format=json"]),
+        };
+        let output = cmd
+            .output()
+            .with_context(|| format!("failed to spawn {:?}", cmd))?;
+        if !output.status.success() {
+            bail!("{:?} failed", cmd);
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        match self {
+            CompilerCache::Ccache => Ok(parse_ccache_stats(&output)),
+            CompilerCache::Sccache => parse_sccache_stats(&output),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompilerCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CompilerCacheStats {
+    /// Counts accumulated since an earlier snapshot
+    pub(crate) fn since(&self, earlier: &CompilerCacheStats) -> CompilerCacheStats {
+        CompilerCacheStats {
+            hits: self.hits.saturating_sub(earlier.hits),
+            misses: self.misses.saturating_sub(earlier.misses),
+        }
+    }
+}
+
+/// Parse tab-separated `ccache --print-stats` output
+fn parse_ccache_stats(output: &str) -> CompilerCacheStats {
+    let mut stats = CompilerCacheStats::default();
+    for line in output.lines() {
+        let (key, value) = match line.split_once('\t') {
+            Some((key, value)) => (key, value.trim().parse::<u64>().unwrap_or(0)),
+            None => continue,
+        };
+        match key {
+            "direct_cache_hit" | "preprocessed_cache_hit" => stats.hits += value,
+            "cache_miss" => stats.misses += value,
+            _ => {}
+        }
+    }
+    stats
+}
+
+/// Parse `sccache --show-stats --stats-format=json` output, which counts
+/// hits and misses per language
+fn parse_sccache_stats(output: &str) -> anyhow::Result<CompilerCacheStats> {
+    let json: serde_json::Value = serde_json::from_str(output).context("invalid sccache stats")?;
+    let sum_counts = |key: &str| {
+        json["stats"][key]["counts"]
+            .as_object()
+            .map(|counts| counts.values().filter_map(|count| count.as_u64()).sum())
+            .unwrap_or(0)
+    };
+    Ok(CompilerCacheStats {
+        hits: sum_counts("cache_hits"),
+        misses: sum_counts("cache_misses"),
+    })
+}
+
+/// User-specified toolchain components. Unspecified components are taken from
+/// the given (or downloaded) wasi-sdk, or looked up in PATH when assembling
+/// a toolchain from system binaries.
+#[derive(Default)]
+pub struct ToolchainOverrides {
+    pub wasi_sdk: Option<PathBuf>,
+    pub cc: Option<PathBuf>,
+    pub ld: Option<PathBuf>,
+    pub ar: Option<PathBuf>,
+    pub ranlib: Option<PathBuf>,
+    pub sysroot: Option<PathBuf>,
+}
+
+impl ToolchainOverrides {
+    fn has_system_component(&self) -> bool {
+        self.cc.is_some()
+            || self.ld.is_some()
+            || self.ar.is_some()
+            || self.ranlib.is_some()
+            || self.sysroot.is_some()
+    }
+}
+
+fn find_wasm_opt() -> anyhow::Result<PathBuf> {
+    which::which("wasm-opt").context("wasm-opt command not found")
+}
+
+fn resolve_system_tool(given: Option<PathBuf>, candidates: &[&str]) -> anyhow::Result<PathBuf> {
+    if let Some(given) = given {
+        return Ok(given);
+    }
+    for candidate in candidates {
+        if let Ok(found) = which::which(candidate) {
+            return Ok(found);
+        }
+    }
+    bail!("none of {} found in PATH", candidates.join(", "))
+}
+
+/// Assemble a toolchain from individually installed system packages
+/// (e.g. clang, lld and wasi-libc shipped by distros)
+pub fn system_toolchain(overrides: ToolchainOverrides) -> anyhow::Result<Toolchain> {
+    let sysroot = if let Some(sysroot) = overrides.sysroot {
+        sysroot
+    } else {
+        bail!("--sysroot is required when not using wasi-sdk")
+    };
+    if !sysroot.exists() {
+        bail!("wasi sysroot {:?} does not exist", sysroot);
+    }
+    let mut toolchain = Toolchain {
+        cc: resolve_system_tool(overrides.cc, &["clang"])?,
+        ld: resolve_system_tool(overrides.ld, &["wasm-ld"])?,
+        ar: resolve_system_tool(overrides.ar, &["llvm-ar", "ar"])?,
+        ranlib: resolve_system_tool(overrides.ranlib, &["llvm-ranlib", "ranlib"])?,
+        sysroot,
+        wasm_opt: find_wasm_opt()?,
+        make: Make::default(),
+        compiler_cache: None,
+        identity: String::from("system"),
+    };
+    toolchain.identity = format!("system:{}", toolchain.components_for_identity());
+    Ok(toolchain)
+}
+
+pub fn install_build_toolchain(
+    workspace: &Workspace,
+    overrides: ToolchainOverrides,
+) -> anyhow::Result<Toolchain> {
     log::info!("install build toolchain...");
-    #[cfg(target_os = "macos")]
-    const WASI_SDK_RELEASE_TARBALL: &str = "https://github.com/WebAssembly/wasi-sdk/releases/download/wasi-sdk-14/wasi-sdk-14.0-macos.tar.gz";
-    #[cfg(target_os = "linux")]
-    const WASI_SDK_RELEASE_TARBALL: &str = "https://github.com/WebAssembly/wasi-sdk/releases/download/wasi-sdk-14/wasi-sdk-14.0-linux.tar.gz";
-    #[cfg(target_os = "windows")]
-    const WASI_SDK_RELEASE_TARBALL: &str = "https://github.com/WebAssembly/wasi-sdk/releases/download/wasi-sdk-14/wasi-sdk-14.0-mingw.tar.gz";
-
-    const WASI_SDK_VERSION: &str = "14.0";
+    let started = Instant::now();
+    if overrides.wasi_sdk.is_none() && overrides.has_system_component() {
+        // nothing is installed, so no phase is recorded
+        return system_toolchain(overrides);
+    }
+    let mut toolchain = if let Some(wasi_sdk) = &overrides.wasi_sdk {
+        let wasi_sdk = wasi_sdk
+            .canonicalize()
+            .with_context(|| format!("wasi-sdk not found at {:?}", wasi_sdk))?;
+        Toolchain::from_wasi_sdk(&wasi_sdk, find_wasm_opt()?)
+    } else {
+        let platform = HostPlatform::detect();
+        let (wasi_sdk, asset, fresh) = download_wasi_sdk(workspace, &platform)?;
+        workspace.record_phase("toolchain install", started.elapsed(), !fresh);
+        let mut toolchain = Toolchain::from_wasi_sdk(&wasi_sdk, find_wasm_opt()?);
+        toolchain.identity = format!("{} ({})", asset, platform);
+        toolchain
+    };
+    let has_component_overrides = overrides.has_system_component();
+    if let Some(cc) = overrides.cc {
+        toolchain.cc = cc;
+    }
+    if let Some(ld) = overrides.ld {
+        toolchain.ld = ld;
+    }
+    if let Some(ar) = overrides.ar {
+        toolchain.ar = ar;
+    }
+    if let Some(ranlib) = overrides.ranlib {
+        toolchain.ranlib = ranlib;
+    }
+    if let Some(sysroot) = overrides.sysroot {
+        toolchain.sysroot = sysroot;
+    }
+    if has_component_overrides {
+        toolchain.identity = format!(
+            "{}:{}",
+            toolchain.identity,
+            toolchain.components_for_identity()
+        );
+    }
+    Ok(toolchain)
+}
+
+/// Operating system and CPU architecture of the machine running rbwasm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPlatform {
+    pub arch: String,
+    pub os: String,
+}
+
+impl HostPlatform {
+    /// Detect the host at runtime rather than trusting the target rbwasm was
+    /// compiled for (e.g. an x86_64 binary running under emulation)
+    pub fn detect() -> HostPlatform {
+        let uname = |flag: &str| -> Option<String> {
+            let output = Command::new("uname").arg(flag).output().ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        let arch = uname("-m").unwrap_or_else(|| std::env::consts::ARCH.to_string());
+        let os = uname("-s").unwrap_or_else(|| std::env::consts::OS.to_string());
+        HostPlatform::new(&arch, &os)
+    }
+
+    pub fn new(arch: &str, os: &str) -> HostPlatform {
+        let arch = match arch {
+            "amd64" | "x86_64" => "x86_64",
+            "arm64" | "aarch64" => "aarch64",
+            other => other,
+        };
+        let os = match os.to_lowercase().as_str() {
+            "darwin" | "macos" => String::from("macos"),
+            "linux" => String::from("linux"),
+            other
+                if other == "windows"
+                    || other.starts_with("mingw")
+                    || other.starts_with("msys")
+                    || other.starts_with("cygwin") =>
+            {
+                String::from("windows")
+            }
+            other => other.to_string(),
+        };
+        HostPlatform {
+            arch: arch.to_string(),
+            os,
+        }
+    }
+
+    pub fn triple(&self) -> String {
+        format!("{}-{}", self.arch, self.os)
+    }
+}
+
+impl fmt::Display for HostPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.triple())
+    }
+}
+
+const WASI_SDK_VERSION: &str = "14.0";
+
+/// Returns the name of the prebuilt wasi-sdk release asset runnable on the given host
+fn wasi_sdk_asset(platform: &HostPlatform) -> Option<String> {
+    let suffix = match (platform.arch.as_str(), platform.os.as_str()) {
+        ("x86_64", "linux") => "linux",
+        // macOS release is x86_64 only, but it runs on Apple Silicon through Rosetta 2
+        ("x86_64", "macos") | ("aarch64", "macos") => "macos",
+        ("x86_64", "windows") => "mingw",
+        _ => return None,
+    };
+    Some(format!("wasi-sdk-{}-{}.tar.gz", WASI_SDK_VERSION, suffix))
+}
+
+/// Download the prebuilt wasi-sdk for the host and returns its root, asset name
+/// and whether it was newly downloaded
+fn download_wasi_sdk(
+    workspace: &Workspace,
+    platform: &HostPlatform,
+) -> anyhow::Result<(PathBuf, String, bool)> {
+    let asset = match wasi_sdk_asset(platform) {
+        Some(asset) => asset,
+        None => bail!(
+            "no prebuilt wasi-sdk {} for {}, pass --wasi-sdk",
+            WASI_SDK_VERSION,
+            platform
+        ),
+    };
+    let major_version = WASI_SDK_VERSION.split('.').next().unwrap();
+    let tarball_url = format!(
+        "https://github.com/WebAssembly/wasi-sdk/releases/download/wasi-sdk-{}/{}",
+        major_version, asset
+    );
     let wasi_sdk_dest = workspace
         .downloads_dir()
-        .join(format!("wasi-sdk-{}", WASI_SDK_VERSION));
-    if !wasi_sdk_dest.exists() {
+        .join(format!("wasi-sdk-{}-{}", WASI_SDK_VERSION, platform));
+    let fresh = !wasi_sdk_dest.exists();
+    if fresh {
         ui_info!(
-            "installing wasi-sdk {} into {:?}",
+            "installing wasi-sdk {} for {} into {:?}",
             WASI_SDK_VERSION,
+            platform,
             relpath_for_display(&wasi_sdk_dest)
         );
         std::fs::create_dir_all(wasi_sdk_dest.as_path())?;
-        let mut tar_gz = reqwest::blocking::get(WASI_SDK_RELEASE_TARBALL)?.error_for_status()?;
+        let mut tar_gz = reqwest::blocking::get(&tarball_url)?.error_for_status()?;
         extract_tarball(&mut tar_gz, &wasi_sdk_dest)?;
     }
 
-    Ok(Toolchain {
-        wasm_opt: which::which("wasm-opt")
-            .with_context(|| format!("wasm-opt command not found"))?,
-        wasi_sdk: wasi_sdk_dest.canonicalize()?,
-    })
+    Ok((wasi_sdk_dest.canonicalize()?, asset, fresh))
+}
+
+/// Minimum baseruby version assumed when the source tree doesn't tell it
+const FALLBACK_MIN_BASERUBY_VERSION: &str = "2.2";
+
+/// Host Ruby used by CRuby's build for code generation
+#[derive(Debug, Clone)]
+pub struct BaseRuby {
+    pub path: PathBuf,
+    pub version: String,
+}
+
+/// Only the version affects build products, so moving the host ruby
+/// doesn't invalidate the build cache
+impl Hash for BaseRuby {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.version.hash(state);
+    }
+}
+
+impl BaseRuby {
+    /// Locate a baseruby from the given path, `BASERUBY` env var or PATH
+    pub fn find(given: Option<PathBuf>) -> anyhow::Result<BaseRuby> {
+        let path = if let Some(given) = given {
+            given
+        } else if let Some(from_env) = std::env::var_os("BASERUBY") {
+            PathBuf::from(from_env)
+        } else {
+            which::which("ruby").context(
+                "baseruby not found in PATH: cross-compiling CRuby requires a host ruby, pass --baseruby",
+            )?
+        };
+        let output = Command::new(&path)
+            .args(["--disable-gems", "-e", "print RUBY_VERSION"])
+            .output()
+            .with_context(|| format!("failed to spawn baseruby {:?}", path))?;
+        if !output.status.success() {
+            bail!("baseruby {:?} is not working: {}", path, output.status);
+        }
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(BaseRuby { path, version })
+    }
+
+    /// Check the version against the requirement declared in the source tree's configure.ac
+    pub fn check_compatibility(&self, src_dir: &Path) -> anyhow::Result<()> {
+        let required = std::fs::read_to_string(src_dir.join("configure.ac"))
+            .ok()
+            .and_then(|configure_ac| required_baseruby_version(&configure_ac))
+            .unwrap_or_else(|| FALLBACK_MIN_BASERUBY_VERSION.to_string());
+        if compare_versions(&self.version, &required) == Ordering::Less {
+            bail!(
+                "baseruby {:?} is {}, but the CRuby source requires {} or later",
+                self.path,
+                self.version,
+                required
+            );
+        }
+        log::info!("using baseruby {:?} ({})", self.path, self.version);
+        Ok(())
+    }
+}
+
+fn required_baseruby_version(configure_ac: &str) -> Option<String> {
+    let pattern = Regex::new(r#"RUBY_VERSION\s*>=\s*\\?["']([0-9.]+)\\?["']"#).unwrap();
+    pattern
+        .captures(configure_ac)
+        .map(|captures| captures[1].to_string())
+}
+
+fn compare_versions(lhs: &str, rhs: &str) -> Ordering {
+    let parse = |v: &str| -> Vec<u32> {
+        v.split('.')
+            .map(|component| component.parse().unwrap_or(0))
+            .collect()
+    };
+    let (mut lhs, mut rhs) = (parse(lhs), parse(rhs));
+    let len = lhs.len().max(rhs.len());
+    lhs.resize(len, 0);
+    rhs.resize(len, 0);
+    lhs.cmp(&rhs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::path::PathBuf;
+
+    use super::{
+        compare_versions, makeflags_controls_jobs, parse_ccache_stats, parse_sccache_stats,
+        required_baseruby_version, wasi_sdk_asset, BaseRuby, CompilerCacheStats, HostPlatform,
+    };
+
+    #[test]
+    fn test_host_platform_normalization() {
+        assert_eq!(
+            HostPlatform::new("arm64", "Darwin").triple(),
+            "aarch64-macos"
+        );
+        assert_eq!(HostPlatform::new("amd64", "Linux").triple(), "x86_64-linux");
+        assert_eq!(
+            HostPlatform::new("x86_64", "MINGW64_NT-10.0").triple(),
+            "x86_64-windows"
+        );
+    }
+
+    #[test]
+    fn test_wasi_sdk_asset() {
+        assert_eq!(
+            wasi_sdk_asset(&HostPlatform::new("x86_64", "Linux")).as_deref(),
+            Some("wasi-sdk-14.0-linux.tar.gz")
+        );
+        assert_eq!(wasi_sdk_asset(&HostPlatform::new("aarch64", "Linux")), None);
+    }
+
+    #[test]
+    fn test_required_baseruby_version() {
+        let configure_ac = r#"AS_IF([test "`$BASERUBY -e 'exit RUBY_VERSION >= "2.5"'`"], [])"#;
+        assert_eq!(
+            required_baseruby_version(configure_ac).as_deref(),
+            Some("2.5")
+        );
+        assert_eq!(required_baseruby_version("AC_INIT(ruby)"), None);
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("3.0.2", "2.2"), Ordering::Greater);
+        assert_eq!(compare_versions("2.10.0", "2.9"), Ordering::Greater);
+        assert_eq!(compare_versions("2.2", "2.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("2.1.9", "2.2"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_makeflags_controls_jobs() {
+        assert!(makeflags_controls_jobs(" -j4 --jobserver-auth=3,4"));
+        assert!(makeflags_controls_jobs("w -- --jobserver-fds=3,4 -j"));
+        assert!(!makeflags_controls_jobs("s -- V=1"));
+        assert!(!makeflags_controls_jobs(""));
+    }
+
+    #[test]
+    fn test_compiler_cache_stats() {
+        let ccache = "stats_updated_timestamp\t1650000000\ndirect_cache_hit\t10\npreprocessed_cache_hit\t2\ncache_miss\t5\n";
+        assert_eq!(
+            parse_ccache_stats(ccache),
+            CompilerCacheStats {
+                hits: 12,
+                misses: 5
+            }
+        );
+        let sccache = r#"{"stats":{"cache_hits":{"counts":{"C/C++":7}},"cache_misses":{"counts":{"C/C++":3,"Rust":1}}}}"#;
+        let stats = parse_sccache_stats(sccache).unwrap();
+        assert_eq!(stats, CompilerCacheStats { hits: 7, misses: 4 });
+        assert_eq!(
+            stats.since(&CompilerCacheStats { hits: 2, misses: 1 }),
+            CompilerCacheStats { hits: 5, misses: 3 }
+        );
+    }
+
+    #[test]
+    fn test_baseruby_cache_key() {
+        let key = |path: &str, version: &str| {
+            let mut hasher = DefaultHasher::new();
+            BaseRuby {
+                path: PathBuf::from(path),
+                version: version.to_string(),
+            }
+            .hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(
+            key("/usr/bin/ruby", "3.1.2"),
+            key("/opt/ruby/bin/ruby", "3.1.2")
+        );
+        assert_ne!(key("/usr/bin/ruby", "3.1.2"), key("/usr/bin/ruby", "2.7.6"));
+    }
 }
@@ -28,3 +28,14 @@ pub(crate) fn trace_command_exec(cmd: &Command, description: &str, cwd: Option<&
 pub(crate) fn info_fmt(args: fmt::Arguments<'_>) {
     eprintln!("{} {}", ansi_term::Style::new().bold().paint("info:"), args);
 }
+
+pub(crate) fn log_excerpt<S: AsRef<str>>(title: &str, lines: &[S]) {
+    eprintln!(
+        "{} {}",
+        ansi_term::Style::new().bold().paint("note:"),
+        title
+    );
+    for line in lines {
+        eprintln!("  | {}", line.as_ref());
+    }
+}
@@ -0,0 +1,719 @@
+//! Inspection of produced wasm modules
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use anyhow::{bail, Context};
+use wasmparser::{
+    DataKind, ExternalKind, ImportSectionEntryType, MemoryType, Name, NameSectionReader, Operator,
+    Parser, Payload, SectionReader, Validator,
+};
+
+/// Sizes of the parts of a module in bytes
+#[derive(Debug, Default)]
+pub struct ModuleSizes {
+    pub total: u64,
+    /// Payload size of each section in order. Custom sections are named
+    /// `custom <name>`.
+    pub sections: Vec<(String, u64)>,
+    /// Body size of each defined function, named after the name section if
+    /// present, otherwise after its index
+    pub functions: Vec<(String, u64)>,
+    /// Whether the module has function names
+    pub has_names: bool,
+}
+
+impl ModuleSizes {
+    pub fn section(&self, name: &str) -> u64 {
+        self.sections
+            .iter()
+            .filter(|(section, _)| section == name)
+            .map(|(_, size)| size)
+            .sum()
+    }
+}
+
+pub fn module_sizes(module: &[u8]) -> anyhow::Result<ModuleSizes> {
+    fn range_len(range: wasmparser::Range) -> u64 {
+        (range.end - range.start) as u64
+    }
+    let mut sizes = ModuleSizes {
+        total: module.len() as u64,
+        ..Default::default()
+    };
+    let mut imported_functions = 0;
+    let mut function_names = HashMap::new();
+    let mut bodies = vec![];
+    for payload in Parser::new(0).parse_all(module) {
+        let (name, size) = match payload? {
+            Payload::TypeSection(reader) => ("type".to_string(), range_len(reader.range())),
+            Payload::ImportSection(reader) => {
+                let size = range_len(reader.range());
+                for import in reader {
+                    if let ImportSectionEntryType::Function(_) = import?.ty {
+                        imported_functions += 1;
+                    }
+                }
+                ("import".to_string(), size)
+            }
+            Payload::FunctionSection(reader) => ("function".to_string(), range_len(reader.range())),
+            Payload::TableSection(reader) => ("table".to_string(), range_len(reader.range())),
+            Payload::MemorySection(reader) => ("memory".to_string(), range_len(reader.range())),
+            Payload::GlobalSection(reader) => ("global".to_string(), range_len(reader.range())),
+            Payload::ExportSection(reader) => ("export".to_string(), range_len(reader.range())),
+            Payload::StartSection { range, .. } => ("start".to_string(), range_len(range)),
+            Payload::ElementSection(reader) => ("element".to_string(), range_len(reader.range())),
+            Payload::DataCountSection { range, .. } => ("datacount".to_string(), range_len(range)),
+            Payload::DataSection(reader) => ("data".to_string(), range_len(reader.range())),
+            Payload::CodeSectionStart { range, .. } => ("code".to_string(), range_len(range)),
+            Payload::CodeSectionEntry(body) => {
+                bodies.push(range_len(body.range()));
+                continue;
+            }
+            Payload::CustomSection {
+                name,
+                data,
+                data_offset,
+                range,
+            } => {
+                if name == "name" {
+                    collect_function_names(data, data_offset, &mut function_names)?;
+                }
+                (format!("custom {}", name), range_len(range))
+            }
+            _ => continue,
+        };
+        sizes.sections.push((name, size));
+    }
+    sizes.has_names = !function_names.is_empty();
+    sizes.functions = bodies
+        .into_iter()
+        .enumerate()
+        .map(|(i, size)| {
+            let index = imported_functions + i as u32;
+            let name = function_names
+                .remove(&index)
+                .unwrap_or_else(|| format!("function[{}]", index));
+            (name, size)
+        })
+        .collect();
+    Ok(sizes)
+}
+
+pub(crate) fn collect_function_names(
+    data: &[u8],
+    data_offset: usize,
+    names: &mut HashMap<u32, String>,
+) -> anyhow::Result<()> {
+    for name in NameSectionReader::new(data, data_offset)? {
+        if let Name::Function(map) = name? {
+            let mut map = map.get_map()?;
+            for _ in 0..map.get_count() {
+                let naming = map.read()?;
+                names.insert(naming.index, naming.name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names of all functions from the name section, or `None` if the module
+/// has no function names
+pub fn function_names(module: &[u8]) -> anyhow::Result<Option<Vec<String>>> {
+    let mut names = HashMap::new();
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::CustomSection {
+            name: "name",
+            data,
+            data_offset,
+            ..
+        } = payload?
+        {
+            collect_function_names(data, data_offset, &mut names)?;
+        }
+    }
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let mut names = names.into_iter().collect::<Vec<_>>();
+    names.sort();
+    Ok(Some(names.into_iter().map(|(_, name)| name).collect()))
+}
+
+/// Imported functions as `module.name`
+pub fn function_imports(module: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut imports = vec![];
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::ImportSection(reader) = payload? {
+            for import in reader {
+                let import = import?;
+                if let ImportSectionEntryType::Function(_) = import.ty {
+                    imports.push(format!("{}.{}", import.module, import.field.unwrap_or("")));
+                }
+            }
+        }
+    }
+    Ok(imports)
+}
+
+const ARCHIVE_MAGIC: &[u8] = b"!<arch>\n";
+
+/// Functions imported by a relocatable object file or by the objects in a
+/// static library, as `module.name`. They are the undefined functions which
+/// the final link resolves or leaves to the host.
+pub fn object_imports(object: &[u8]) -> anyhow::Result<Vec<String>> {
+    let archive = match object.strip_prefix(ARCHIVE_MAGIC) {
+        Some(archive) => archive,
+        None => return function_imports(object),
+    };
+    let mut imports = vec![];
+    let mut rest = archive;
+    while rest.len() >= 60 {
+        let (header, body) = rest.split_at(60);
+        let size: usize = std::str::from_utf8(&header[48..58])?
+            .trim()
+            .parse()
+            .context("invalid archive member size")?;
+        let mut member = body.get(..size).context("truncated archive member")?;
+        // BSD archives put long names before the contents
+        if let Some(name_len) = header.strip_prefix(b"#1/") {
+            let name_len: usize = std::str::from_utf8(&name_len[..13])?
+                .trim()
+                .parse()
+                .context("invalid archive member name")?;
+            member = member.get(name_len..).context("truncated archive member")?;
+        }
+        // symbol tables and name tables are not objects
+        if member.starts_with(b"\0asm") {
+            imports.extend(function_imports(member)?);
+        }
+        // members are aligned to 2 bytes
+        rest = body.get(size + size % 2..).unwrap_or_default();
+    }
+    Ok(imports)
+}
+
+/// Fail if the module at `path` is invalid, naming the function at fault
+pub fn validate(path: &Path) -> anyhow::Result<()> {
+    let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+    validate_module(&module).with_context(|| format!("{:?} is not a valid module", path))
+}
+
+fn validate_module(module: &[u8]) -> anyhow::Result<()> {
+    let error = match Validator::new().validate_all(module) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+    match function_at(module, error.offset()) {
+        Some((index, Some(name))) => bail!(
+            "function {} ({}) at offset {:#x}: {}",
+            index,
+            name,
+            error.offset(),
+            error.message()
+        ),
+        Some((index, None)) => bail!(
+            "function {} at offset {:#x}: {}",
+            index,
+            error.offset(),
+            error.message()
+        ),
+        None => bail!("at offset {:#x}: {}", error.offset(), error.message()),
+    }
+}
+
+/// Index and name of the function whose body contains `offset`, as far as
+/// the module can be parsed
+fn function_at(module: &[u8], offset: usize) -> Option<(u32, Option<String>)> {
+    let mut imported_functions = 0;
+    let mut found = None;
+    let mut names = HashMap::new();
+    let mut defined = 0;
+    for payload in Parser::new(0).parse_all(module) {
+        match payload.ok()? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let ImportSectionEntryType::Function(_) = import.ok()?.ty {
+                        imported_functions += 1;
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let range = body.range();
+                if (range.start..range.end).contains(&offset) {
+                    found = Some(imported_functions + defined);
+                }
+                defined += 1;
+            }
+            Payload::CustomSection {
+                name: "name",
+                data,
+                data_offset,
+                ..
+            } => {
+                // names are only for the message
+                let _ = collect_function_names(data, data_offset, &mut names);
+            }
+            _ => {}
+        }
+    }
+    found.map(|index| (index, names.remove(&index)))
+}
+
+/// An import or export with the kind of its item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceItem {
+    /// Module of an import
+    pub module: Option<String>,
+    pub name: String,
+    pub kind: &'static str,
+}
+
+impl fmt::Display for InterfaceItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.module {
+            Some(module) => write!(f, "{} {}.{}", self.kind, module, self.name),
+            None => write!(f, "{} {}", self.kind, self.name),
+        }
+    }
+}
+
+/// Imports and exports of a module
+#[derive(Debug, Default)]
+pub struct ModuleInterface {
+    pub imports: Vec<InterfaceItem>,
+    pub exports: Vec<InterfaceItem>,
+}
+
+pub fn module_interface(module: &[u8]) -> anyhow::Result<ModuleInterface> {
+    fn import_kind(ty: &ImportSectionEntryType) -> &'static str {
+        match ty {
+            ImportSectionEntryType::Function(_) => "func",
+            ImportSectionEntryType::Table(_) => "table",
+            ImportSectionEntryType::Memory(_) => "memory",
+            ImportSectionEntryType::Tag(_) => "tag",
+            ImportSectionEntryType::Global(_) => "global",
+            ImportSectionEntryType::Module(_) => "module",
+            ImportSectionEntryType::Instance(_) => "instance",
+        }
+    }
+    fn export_kind(kind: &ExternalKind) -> &'static str {
+        match kind {
+            ExternalKind::Function => "func",
+            ExternalKind::Table => "table",
+            ExternalKind::Memory => "memory",
+            ExternalKind::Tag => "tag",
+            ExternalKind::Global => "global",
+            ExternalKind::Type => "type",
+            ExternalKind::Module => "module",
+            ExternalKind::Instance => "instance",
+        }
+    }
+    let mut interface = ModuleInterface::default();
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    interface.imports.push(InterfaceItem {
+                        module: Some(import.module.to_string()),
+                        name: import.field.unwrap_or("").to_string(),
+                        kind: import_kind(&import.ty),
+                    });
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    interface.exports.push(InterfaceItem {
+                        module: None,
+                        name: export.field.to_string(),
+                        kind: export_kind(&export.kind),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(interface)
+}
+
+impl ModuleInterface {
+    pub fn print(&self) {
+        eprintln!("imports ({}):", self.imports.len());
+        for import in &self.imports {
+            eprintln!("  {}", import);
+        }
+        eprintln!("exports ({}):", self.exports.len());
+        for export in &self.exports {
+            eprintln!("  {}", export);
+        }
+    }
+
+    /// Imports outside WASI and the allow-list, whose entries are either
+    /// `MODULE` or `MODULE.NAME`
+    pub fn unexpected_imports(&self, allowed: &[String]) -> Vec<&InterfaceItem> {
+        self.imports
+            .iter()
+            .filter(|import| {
+                let module = import.module.as_deref().unwrap_or("");
+                let is_allowed = module == WASI_MODULE
+                    || allowed.iter().any(|entry| match entry.split_once('.') {
+                        Some((allowed_module, name)) => {
+                            allowed_module == module && name == import.name
+                        }
+                        None => entry == module,
+                    });
+                !is_allowed
+            })
+            .collect()
+    }
+}
+
+/// Module of WASI imports, which are always allowed
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// Fail if the module at `path` imports anything unexpected
+pub fn verify_imports(path: &Path, allowed: &[String]) -> anyhow::Result<()> {
+    let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+    let interface =
+        module_interface(&module).with_context(|| format!("failed to parse {:?}", path))?;
+    let unexpected = interface.unexpected_imports(allowed);
+    if !unexpected.is_empty() {
+        bail!(
+            "{:?} has imports outside {} (allow them with --allow-import): {}",
+            path,
+            WASI_MODULE,
+            unexpected
+                .iter()
+                .map(|import| import.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Names of all exports of a module
+pub fn export_names(module: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut names = vec![];
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::ExportSection(reader) = payload? {
+            for export in reader {
+                names.push(export?.field.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Fail if any of the required exports is missing in the module at `path`
+pub fn verify_exports<S: AsRef<str>>(path: &Path, required: &[S]) -> anyhow::Result<()> {
+    let module = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+    let names = export_names(&module).with_context(|| format!("failed to parse {:?}", path))?;
+    let missing = required
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|required| !names.iter().any(|name| name == required))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "{:?} doesn't export requested symbols: {}",
+            path,
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// A section as encoded in a module
+#[derive(Debug)]
+pub struct RawSection<'a> {
+    pub id: u8,
+    /// Name of a custom section
+    pub custom_name: Option<&'a str>,
+    /// Offset of the section contents in the module
+    pub payload_offset: usize,
+    /// Section contents, after the name for custom sections
+    pub payload: &'a [u8],
+    /// The whole section including its id and size
+    pub encoded: &'a [u8],
+}
+
+/// Split a module into its sections without decoding them
+pub fn raw_sections(module: &[u8]) -> anyhow::Result<Vec<RawSection<'_>>> {
+    if module.len() < 8 || &module[..4] != b"\0asm" {
+        bail!("not a wasm module");
+    }
+    let mut sections = vec![];
+    let mut pos = 8;
+    while pos < module.len() {
+        let start = pos;
+        let id = module[pos];
+        pos += 1;
+        let size = read_leb_u32(module, &mut pos)? as usize;
+        let end = pos + size;
+        if end > module.len() {
+            bail!("section at {} exceeds the module", start);
+        }
+        let mut custom_name = None;
+        let mut payload_offset = pos;
+        if id == 0 {
+            let name_len = read_leb_u32(module, &mut payload_offset)? as usize;
+            let name = module
+                .get(payload_offset..payload_offset + name_len)
+                .filter(|_| payload_offset + name_len <= end)
+                .context("custom section name exceeds the section")?;
+            custom_name = Some(std::str::from_utf8(name).context("invalid custom section name")?);
+            payload_offset += name_len;
+        }
+        sections.push(RawSection {
+            id,
+            custom_name,
+            payload_offset,
+            payload: &module[payload_offset..end],
+            encoded: &module[start..end],
+        });
+        pos = end;
+    }
+    Ok(sections)
+}
+
+pub(crate) fn read_leb_u32(bytes: &[u8], pos: &mut usize) -> anyhow::Result<u32> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*pos).context("unexpected end of module")?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    bail!("invalid LEB128 at {}", *pos)
+}
+
+pub(crate) fn write_leb_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Encode a custom section with the given name and contents
+pub fn encode_custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut payload = vec![];
+    write_leb_u32(&mut payload, name.len() as u32);
+    payload.extend(name.as_bytes());
+    payload.extend(data);
+    let mut section = vec![0];
+    write_leb_u32(&mut section, payload.len() as u32);
+    section.extend(payload);
+    section
+}
+
+/// Linear memory of a module as laid out by the linker
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MemoryLayout {
+    pub imported: bool,
+    pub exported: bool,
+    /// Initial size in pages
+    pub initial_pages: u64,
+    /// Maximum size in pages, if limited
+    pub maximum_pages: Option<u64>,
+    /// Lowest and highest addresses of active data segments with constant
+    /// offsets, if any
+    pub data_range: Option<(u64, u64)>,
+}
+
+/// Memory layout of a module with a single memory
+pub fn memory_layout(module: &[u8]) -> anyhow::Result<MemoryLayout> {
+    let mut memories = vec![];
+    let mut layout = MemoryLayout::default();
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let ImportSectionEntryType::Memory(ty) = import?.ty {
+                        memories.push((true, ty));
+                    }
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for ty in reader {
+                    memories.push((false, ty?));
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    if let ExternalKind::Memory = export?.kind {
+                        layout.exported = true;
+                    }
+                }
+            }
+            Payload::DataSection(reader) => {
+                for data in reader {
+                    let data = data?;
+                    let init_expr = match data.kind {
+                        DataKind::Active { init_expr, .. } => init_expr,
+                        DataKind::Passive => continue,
+                    };
+                    let start = match init_expr.get_operators_reader().read()? {
+                        Operator::I32Const { value } => value as u32 as u64,
+                        _ => continue,
+                    };
+                    let end = start + data.data.len() as u64;
+                    layout.data_range = Some(match layout.data_range {
+                        Some((lo, hi)) => (lo.min(start), hi.max(end)),
+                        None => (start, end),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    let (
+        imported,
+        MemoryType {
+            initial, maximum, ..
+        },
+    ) = match memories.as_slice() {
+        [memory] => *memory,
+        [] => bail!("module has no memory"),
+        _ => bail!("module has {} memories", memories.len()),
+    };
+    layout.imported = imported;
+    layout.initial_pages = initial;
+    layout.maximum_pages = maximum;
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_custom_section, export_names, memory_layout, module_interface, module_sizes,
+        object_imports, raw_sections, validate_module, MemoryLayout,
+    };
+    use rbwasm_test_support::wasm::{module_exporting, module_importing};
+
+    #[test]
+    fn test_export_names() {
+        let module = module_exporting("ruby_init");
+        assert_eq!(export_names(&module).unwrap(), vec!["ruby_init"]);
+    }
+
+    #[test]
+    fn test_module_sizes() {
+        let module = module_exporting("ruby_init");
+        let sizes = module_sizes(&module).unwrap();
+        assert_eq!(sizes.total, module.len() as u64);
+        assert_eq!(sizes.section("export"), 13);
+        assert_eq!(sizes.functions, vec![("function[0]".to_string(), 2)]);
+        assert!(!sizes.has_names);
+    }
+
+    #[test]
+    fn test_memory_layout() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // memory section: initial 2 pages, maximum 4 pages
+        module.extend([0x05, 0x04, 0x01, 0x01, 0x02, 0x04]);
+        // export section: memory 0 as "memory"
+        module.extend([0x07, 0x0a, 0x01, 0x06]);
+        module.extend(b"memory");
+        module.extend([0x02, 0x00]);
+        // data section: 3 bytes at 1024 and 1 byte at 2048
+        module.extend([0x0b, 0x11, 0x02]);
+        module.extend([0x00, 0x41, 0x80, 0x08, 0x0b, 0x03, 1, 2, 3]);
+        module.extend([0x00, 0x41, 0x80, 0x10, 0x0b, 0x01, 4]);
+        assert_eq!(
+            memory_layout(&module).unwrap(),
+            MemoryLayout {
+                imported: false,
+                exported: true,
+                initial_pages: 2,
+                maximum_pages: Some(4),
+                data_range: Some((1024, 2049)),
+            }
+        );
+        assert!(memory_layout(&module_exporting("_start")).is_err());
+    }
+
+    #[test]
+    fn test_raw_sections() {
+        let mut module = module_exporting("_start");
+        module.extend(encode_custom_section("build_id", &[0x02, 0xab, 0xcd]));
+        let sections = raw_sections(&module).unwrap();
+        assert_eq!(
+            sections.iter().map(|s| s.id).collect::<Vec<_>>(),
+            vec![1, 3, 7, 10, 0]
+        );
+        let custom = sections.last().unwrap();
+        assert_eq!(custom.custom_name, Some("build_id"));
+        assert_eq!(custom.payload, &[0x02, 0xab, 0xcd]);
+        let encoded = sections.iter().flat_map(|s| s.encoded).copied();
+        assert!(module[8..].iter().copied().eq(encoded));
+        assert!(raw_sections(&module[..module.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_validate_module() {
+        let module = module_exporting("_start");
+        validate_module(&module).unwrap();
+        // body of function 0 ends with an i32 left on the stack
+        let mut invalid = module[..module.len() - 6].to_vec();
+        invalid.extend([0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x00, 0x0b]);
+        let error = validate_module(&invalid).unwrap_err().to_string();
+        assert!(error.starts_with("function 0 at offset"), "{}", error);
+    }
+
+    #[test]
+    fn test_unexpected_imports() {
+        let module = module_importing(
+            &[("wasi_snapshot_preview1", "proc_exit"), ("env", "foo")],
+            Some(("env", "memory")),
+        );
+        let interface = module_interface(&module).unwrap();
+        assert_eq!(interface.imports.len(), 3);
+        let unexpected = |allowed: &[&str]| {
+            let allowed = allowed.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            interface
+                .unexpected_imports(&allowed)
+                .iter()
+                .map(|import| import.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(unexpected(&[]), vec!["func env.foo", "memory env.memory"]);
+        assert_eq!(unexpected(&["env.memory"]), vec!["func env.foo"]);
+        assert!(unexpected(&["env"]).is_empty());
+    }
+
+    #[test]
+    fn test_object_imports() {
+        let object = module_importing(&[("env", "host_log")], None);
+        assert_eq!(object_imports(&object).unwrap(), vec!["env.host_log"]);
+
+        let mut archive = b"!<arch>\n".to_vec();
+        for (name, member) in [("/", &b"symbols"[..]), ("binding.o/", &object)] {
+            archive.extend(
+                format!(
+                    "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                    name,
+                    0,
+                    0,
+                    0,
+                    644,
+                    member.len()
+                )
+                .as_bytes(),
+            );
+            archive.extend(member);
+            if member.len() % 2 == 1 {
+                archive.push(b'\n');
+            }
+        }
+        assert_eq!(object_imports(&archive).unwrap(), vec!["env.host_log"]);
+    }
+}
@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use rbwasm::{build_cruby, toolchain::Toolchain, BuildSource, Workspace, CRubyBuildInput};
+use rbwasm::{build_cruby, is_cruby_cached, toolchain::Toolchain, BuildSource, Workspace, CRubyBuildInput};
 use rbwasm_test_support::init_workspace;
 
 fn fakeruby() -> PathBuf {
@@ -19,20 +19,38 @@ fn test_build_cruby_cached() {
     init_workspace!();
     let workspace =
         Workspace::create(PathBuf::from(".rbwasm").canonicalize().unwrap(), true).unwrap();
-    let toolchain = Toolchain {
-        wasm_opt: PathBuf::from("fake-wasm-opt"),
-        wasi_sdk: PathBuf::from("fake-wasi-sdk"),
-    };
+    let toolchain =
+        Toolchain::from_wasi_sdk(Path::new("fake-wasi-sdk"), PathBuf::from("fake-wasm-opt"));
     let build_source = BuildSource::Dir { path: fakeruby };
     let input = CRubyBuildInput {
         source: build_source,
+        prefix: PathBuf::from("/usr/local"),
         asyncify_stack_size: 0,
         enabled_extentions: vec![],
+        gem_exts: vec![],
+        baseruby: None,
+        reproducible: None,
         extra_cc_args: &[],
+        extra_configure_args: &[],
+        configure_env: &[],
+        transient_heap_total_size: None,
+        make_vars: &[],
+        command_overrides: &[],
+        profile: Default::default(),
     };
 
+    assert!(!is_cruby_cached(&workspace, &toolchain, &input));
     let result = build_cruby(&workspace, &toolchain, &input).unwrap();
     assert_eq!(result.cached, false);
+    assert!(is_cruby_cached(&workspace, &toolchain, &input));
     let result = build_cruby(&workspace, &toolchain, &input).unwrap();
     assert_eq!(result.cached, true);
+
+    let phases = workspace.phases();
+    let phase_names = phases.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        phase_names,
+        vec!["source copy", "autogen", "configure", "make", "cruby build"]
+    );
+    assert!(phases.last().unwrap().cached);
 }
@@ -8,23 +8,50 @@ fn test_build_cruby() {
     let space = init_workspace!();
     let workspace =
         Workspace::create(PathBuf::from(".rbwasm").canonicalize().unwrap(), true).unwrap();
-    let toolchain =
-        toolchain::install_build_toolchain(&workspace).expect("failed toolchain install");
+    let toolchain = toolchain::install_build_toolchain(&workspace, Default::default())
+        .expect("failed toolchain install");
     let ruby_source = BuildSource::GitHub {
         owner: String::from("kateinoigakukun"),
         repo: String::from("ruby"),
         git_ref: String::from("9bcc194dc3c12f017a41b6287f85b58f2c487bf8"),
     };
-    build_cruby(
+    let cruby = build_cruby(
         &workspace,
         &toolchain,
         &CRubyBuildInput {
             source: ruby_source,
+            prefix: PathBuf::from("/usr/local"),
             asyncify_stack_size: 0,
             enabled_extentions: vec![],
+            gem_exts: vec![],
+            baseruby: None,
+            reproducible: None,
             extra_cc_args: &[],
+            extra_configure_args: &[],
+            configure_env: &[],
+            transient_heap_total_size: None,
+            make_vars: &[],
+            command_overrides: &[],
+            profile: Default::default(),
         },
     )
     .expect("failed build cruby");
+
+    // a reactor links and has the exports verified after building variants
+    let shim = build_reactor_shim(&workspace, &toolchain, &cruby, &[])
+        .expect("failed to compile reactor shim");
+    let input = LinkerInput {
+        stack_size: 16 * 1024 * 1024,
+        memory: Default::default(),
+        exec_model: ExecModel::Reactor,
+        exports: &[],
+        raw_objects: vec![("reactor.o".to_string(), shim)],
+        object_files: vec![],
+        extra_args: &[],
+    };
+    let output = PathBuf::from("reactor.wasm");
+    link_executable(&workspace, &toolchain, &cruby, &input, &output)
+        .expect("failed to link reactor");
+    wasm::verify_exports(&output, ExecModel::Reactor.exports()).expect("reactor misses exports");
     drop(space)
 }